@@ -0,0 +1,213 @@
+// Throughput of the work-stealing ThreadPool against the design it replaced
+//
+// Run it with `cargo bench`. Every round pushes JOBS jobs of the same size from
+// one thread, like the accept loop does, and the clock stops when the pool has
+// run all of them. The best of ROUNDS is printed, in jobs per millisecond.
+//
+// The difference shows with many cores and short jobs, which is when the old
+// shared lock was fought over. On a single core there is nobody to fight with,
+// and the old design wins by being simpler.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use server::ThreadPool;
+
+const JOBS: usize = 100_000;
+const ROUNDS: usize = 5;
+// How many loop iterations one job spins for, from "nothing" to a few microseconds
+const JOB_SIZES: &[u64] = &[0, 100, 1_000, 10_000];
+
+// The pool as it was before the scheduler: every worker locks the same receiver
+// Only the println! for every job is left out, with it both designs would
+// measure how fast the terminal is
+mod channel_pool {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    type Job = Box<dyn FnOnce() + Send + 'static>;
+
+    enum Message {
+        NewJob(Job),
+        Terminate,
+    }
+
+    pub struct ThreadPool {
+        workers: Vec<Worker>,
+        sender: mpsc::Sender<Message>,
+    }
+
+    struct Worker {
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl ThreadPool {
+        pub fn new(size: usize) -> ThreadPool {
+            let (sender, receiver) = mpsc::channel();
+            let receiver = Arc::new(Mutex::new(receiver));
+
+            let workers = (0..size)
+                .map(|_| Worker::new(Arc::clone(&receiver)))
+                .collect();
+
+            ThreadPool { workers, sender }
+        }
+
+        pub fn execute<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+        {
+            self.sender.send(Message::NewJob(Box::new(f))).unwrap();
+        }
+    }
+
+    impl Drop for ThreadPool {
+        fn drop(&mut self) {
+            for _ in &self.workers {
+                self.sender.send(Message::Terminate).unwrap();
+            }
+            for worker in &mut self.workers {
+                if let Some(thread) = worker.thread.take() {
+                    thread.join().unwrap();
+                }
+            }
+        }
+    }
+
+    impl Worker {
+        fn new(receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+            let thread = thread::spawn(move || loop {
+                let message = receiver.lock().unwrap().recv().unwrap();
+                match message {
+                    Message::NewJob(job) => job(),
+                    Message::Terminate => break,
+                }
+            });
+
+            Worker {
+                thread: Some(thread),
+            }
+        }
+    }
+}
+
+// Something the optimizer can't throw away
+fn work(iterations: u64) {
+    let mut sum = 0u64;
+    for i in 0..iterations {
+        sum = sum.wrapping_add(black_box(i));
+    }
+    black_box(sum);
+}
+
+// Both pools run every queued job before their drop returns
+fn channel_round(workers: usize, size: u64) -> Duration {
+    let pool = channel_pool::ThreadPool::new(workers);
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        pool.execute(move || work(size));
+    }
+    drop(pool);
+    start.elapsed()
+}
+
+fn stealing_round(workers: usize, size: u64) -> Duration {
+    let pool = ThreadPool::build(workers).unwrap();
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        pool.execute(move || work(size));
+    }
+    drop(pool);
+    start.elapsed()
+}
+
+// Jobs that start more jobs, which the old design could only send back through the channel
+fn nested_round(workers: usize) -> (Duration, Duration) {
+    const PARENTS: usize = 100;
+    let children = JOBS / PARENTS;
+
+    let pool = Arc::new(channel_pool::ThreadPool::new(workers));
+    let start = Instant::now();
+    for _ in 0..PARENTS {
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            for _ in 0..children {
+                inner.execute(|| work(100));
+            }
+        });
+    }
+    let channel = wait_and_drop(pool, start);
+
+    let pool = Arc::new(ThreadPool::build(workers).unwrap());
+    let start = Instant::now();
+    for _ in 0..PARENTS {
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            for _ in 0..children {
+                inner.execute(|| work(100));
+            }
+        });
+    }
+    let stealing = wait_and_drop(pool, start);
+
+    (channel, stealing)
+}
+
+// The parents hold handles to the pool, the last one has to be dropped here
+// and not on a worker, where the pool would wait for itself
+fn wait_and_drop<P>(pool: Arc<P>, start: Instant) -> Duration {
+    while Arc::strong_count(&pool) > 1 {
+        thread::yield_now();
+    }
+    drop(pool);
+    start.elapsed()
+}
+
+fn best(mut round: impl FnMut() -> Duration) -> Duration {
+    (0..ROUNDS).map(|_| round()).min().unwrap()
+}
+
+fn per_ms(elapsed: Duration) -> f64 {
+    JOBS as f64 / (elapsed.as_secs_f64() * 1000.0)
+}
+
+fn main() {
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+
+    // Measured first and printed at the end, so the table comes out in one piece
+    let mut rows = Vec::new();
+    for &size in JOB_SIZES {
+        let channel = best(|| channel_round(workers, size));
+        let stealing = best(|| stealing_round(workers, size));
+        rows.push((format!("{} iterations", size), channel, stealing));
+    }
+
+    let mut channel = Duration::MAX;
+    let mut stealing = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let (c, s) = nested_round(workers);
+        channel = channel.min(c);
+        stealing = stealing.min(s);
+    }
+    rows.push(("nested".to_string(), channel, stealing));
+
+    println!(
+        "\n{} jobs per round, {} workers, best of {} rounds\n",
+        JOBS, workers, ROUNDS
+    );
+    println!(
+        "{:<16} {:>16} {:>16} {:>9}",
+        "job size", "channel jobs/ms", "stealing jobs/ms", "speedup"
+    );
+    for (name, channel, stealing) in rows {
+        println!(
+            "{:<16} {:>16.0} {:>16.0} {:>8.2}x",
+            name,
+            per_ms(channel),
+            per_ms(stealing),
+            channel.as_secs_f64() / stealing.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,317 @@
+// One line for every request the server answers
+//
+// Common and Combined are the formats Apache made popular, so the usual log
+// tools can read them. Both get the time the request took, in seconds, added
+// at the end of the line, like nginx's $request_time. JSON has one object per
+// line with the same information under names.
+
+use std::fmt::{self, Write as _};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::date::{format_log_date, format_rfc3339};
+use crate::request::Request;
+use crate::response::Status;
+
+/// How the lines of the access log look
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326 0.001`
+    Common,
+    /// Common, plus the `Referer` and `User-Agent` headers before the duration
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format.to_ascii_lowercase().as_str() {
+            "common" => Some(LogFormat::Common),
+            "combined" => Some(LogFormat::Combined),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What the access log records about one answered request
+#[derive(Debug, Clone, Copy)]
+pub struct AccessRecord<'a> {
+    pub client: Option<SocketAddr>,
+    /// When the request arrived
+    pub time: SystemTime,
+    /// `None` when the request could not be parsed
+    pub request: Option<&'a Request>,
+    pub status: Status,
+    /// The size of the body that was sent, 0 for HEAD
+    pub bytes: u64,
+    /// From the moment the request was read until the answer was written
+    pub duration: Duration,
+}
+
+/// Writes access log lines to a file, or to anything that implements `Write`
+pub struct AccessLog {
+    format: LogFormat,
+    // Connections on different workers log at the same time
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// Appends to the file at `path`, creating it if needed, "-" means stdout
+    pub fn open(path: &Path, format: LogFormat) -> io::Result<AccessLog> {
+        if path == Path::new("-") {
+            return Ok(AccessLog::new(io::stdout(), format));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(file, format))
+    }
+
+    pub fn new(out: impl Write + Send + 'static, format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn record(&self, record: &AccessRecord) {
+        let line = self.format_line(record);
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        // A full disk shouldn't take the requests down with it
+        if let Err(err) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            crate::warn!("Failed to write the access log: {}", err);
+        }
+    }
+
+    /// The line for `record`, with the newline at the end
+    pub fn format_line(&self, record: &AccessRecord) -> String {
+        match self.format {
+            LogFormat::Common => common(record, false),
+            LogFormat::Combined => common(record, true),
+            LogFormat::Json => json(record),
+        }
+    }
+}
+
+// The writer has no Debug, so only the format is shown
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+fn common(record: &AccessRecord, combined: bool) -> String {
+    let client = record
+        .client
+        .map_or("-".to_string(), |client| client.ip().to_string());
+    let request_line = match record.request {
+        Some(request) => format!(
+            "{} {} {}",
+            request.method.as_str(),
+            target(request),
+            request.version.as_str()
+        ),
+        None => "-".to_string(),
+    };
+    // CLF writes "-" instead of 0 bytes
+    let bytes = match record.bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {}",
+        client,
+        format_log_date(record.time),
+        escape(&request_line),
+        record.status.code(),
+        bytes
+    );
+    if combined {
+        let header = |name| {
+            record
+                .request
+                .and_then(|request| request.header(name))
+                .unwrap_or("-")
+        };
+        let _ = write!(
+            line,
+            " \"{}\" \"{}\"",
+            escape(header("Referer")),
+            escape(header("User-Agent"))
+        );
+    }
+    let _ = writeln!(line, " {:.3}", record.duration.as_secs_f64());
+    line
+}
+
+fn json(record: &AccessRecord) -> String {
+    let request = record.request;
+    let header = |name| request.and_then(|request| request.header(name));
+
+    let mut line = String::from("{");
+    let _ = write!(
+        line,
+        "\"time\":{}",
+        json_string(&format_rfc3339(record.time))
+    );
+    let client = record.client.map(|client| client.ip().to_string());
+    let _ = write!(line, ",\"client\":{}", json_option(client.as_deref()));
+    let method = request.map(|request| request.method.as_str());
+    let _ = write!(line, ",\"method\":{}", json_option(method));
+    let path = request.map(|request| request.path.as_str());
+    let _ = write!(line, ",\"path\":{}", json_option(path));
+    let query = request.and_then(|request| request.query.as_deref());
+    let _ = write!(line, ",\"query\":{}", json_option(query));
+    let version = request.map(|request| request.version.as_str());
+    let _ = write!(line, ",\"version\":{}", json_option(version));
+    let _ = write!(line, ",\"status\":{}", record.status.code());
+    let _ = write!(line, ",\"bytes\":{}", record.bytes);
+    let _ = write!(
+        line,
+        ",\"duration_ms\":{:.3}",
+        record.duration.as_secs_f64() * 1000.0
+    );
+    let _ = write!(line, ",\"referer\":{}", json_option(header("Referer")));
+    let _ = write!(
+        line,
+        ",\"user_agent\":{}",
+        json_option(header("User-Agent"))
+    );
+    line.push_str("}\n");
+    line
+}
+
+fn target(request: &Request) -> String {
+    match &request.query {
+        Some(query) => format!("{}?{}", request.path, query),
+        None => request.path.clone(),
+    }
+}
+
+// Quotes and control characters are escaped like Apache does, so a client
+// can't end the quoted field early or forge a line of its own
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_option(text: Option<&str>) -> String {
+    text.map_or("null".to_string(), json_string)
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Method, Version};
+    use crate::Headers;
+    use std::time::UNIX_EPOCH;
+
+    fn request() -> Request {
+        let mut headers = Headers::new();
+        headers.insert("User-Agent", "curl/8.0 \"quoted\"");
+        Request {
+            method: Method::Get,
+            path: "/search".to_string(),
+            query: Some("q=rust".to_string()),
+            version: Version::Http11,
+            headers,
+            body: Vec::new(),
+        }
+    }
+
+    fn record(request: Option<&Request>) -> AccessRecord<'_> {
+        AccessRecord {
+            client: Some("127.0.0.1:51234".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request,
+            status: Status::Ok,
+            bytes: 2326,
+            duration: Duration::from_micros(1200),
+        }
+    }
+
+    fn line(format: LogFormat, record: &AccessRecord) -> String {
+        AccessLog::new(io::sink(), format).format_line(record)
+    }
+
+    #[test]
+    fn common_and_combined() {
+        let request = request();
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /search?q=rust HTTP/1.1\" 200 2326 0.001\n",
+            line(LogFormat::Common, &record(Some(&request)))
+        );
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /search?q=rust HTTP/1.1\" 200 2326 \
+             \"-\" \"curl/8.0 \\\"quoted\\\"\" 0.001\n",
+            line(LogFormat::Combined, &record(Some(&request)))
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let request = request();
+        assert_eq!(
+            "{\"time\":\"2000-10-10T13:55:36.000Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\",\
+             \"path\":\"/search\",\"query\":\"q=rust\",\"version\":\"HTTP/1.1\",\"status\":200,\
+             \"bytes\":2326,\"duration_ms\":1.200,\"referer\":null,\
+             \"user_agent\":\"curl/8.0 \\\"quoted\\\"\"}\n",
+            line(LogFormat::Json, &record(Some(&request)))
+        );
+    }
+
+    #[test]
+    fn unparsed_requests_are_a_dash() {
+        let mut record = record(None);
+        record.status = Status::BadRequest;
+        record.bytes = 0;
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 - \"-\" \"-\" 0.001\n",
+            line(LogFormat::Combined, &record)
+        );
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!("a\\x0ab\\\\", escape("a\nb\\"));
+        assert_eq!("\"a\\nb\\u0001\"", json_string("a\nb\u{1}"));
+    }
+}
@@ -1,79 +1,329 @@
-use server::ThreadPool;
+use server::config::USAGE;
+use server::connection::{reject_connection, Connection, ConnectionOptions};
+use server::{error, info, warn};
+use server::{
+    AccessLog, CacheControl, Certificate, Compression, Config, ConfigError, HttpsRedirect, Limits,
+    OverflowPolicy, PoolHandle, PoolMonitor, Priority, Request, Response, Router, Shutdown,
+    StaticFiles, Status, ThreadPool, Tls,
+};
+use std::env;
 use std::fs;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 fn main() {
-    // First of all we need to define the TCP listener
-    let address = "127.0.0.1:7878";
+    // The settings come from server.toml (or --config) and the command-line flags
+    let config = Config::from_args(env::args()).unwrap_or_else(|err| {
+        if let ConfigError::HelpRequested = err {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        error!("Problem with the configuration: {}", err);
+        process::exit(1);
+    });
+    server::log::set_level(config.log_level);
 
-    let listener = TcpListener::bind(&address).unwrap();
-
-    let pool = ThreadPool::new(4); // Creating a thread pool with a fixed number of threads
+    // Every answered request gets a line in the access log, if there is one
+    let access_log = config.access_log.as_ref().map(|path| {
+        let access_log = AccessLog::open(path, config.access_log_format).unwrap_or_else(|err| {
+            error!("Can't open the access log {}: {}", path.display(), err);
+            process::exit(1);
+        });
+        Arc::new(access_log)
+    });
 
-    // We need to iterate over the incoming connections
-    // take(2) ensures that the listener will only handle two connections
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    // The certificates are read once, every HTTPS connection shares them
+    let tls = (!config.tls_listen.is_empty()).then(|| {
+        load_tls(&config).unwrap_or_else(|err| {
+            error!("Problem with the TLS certificates: {}", err);
+            process::exit(1);
+        })
+    });
 
-        // We could create a new thread for each connection,
-        // but this is not a good idea because it could lead to a DoS attack
+    // First of all we need to define the TCP listeners, one for each address
+    // The HTTPS ones come with what they need for the handshake
+    let plain = config.listen.iter().map(|address| (address, None));
+    let encrypted = config
+        .tls_listen
+        .iter()
+        .map(|address| (address, tls.clone()));
+    let listeners: Vec<(TcpListener, Option<Tls>)> = plain
+        .chain(encrypted)
+        .map(|(address, tls)| {
+            let listener = TcpListener::bind(address).unwrap_or_else(|err| {
+                error!("Can't listen on {}: {}", address, err);
+                process::exit(1);
+            });
+            (listener, tls)
+        })
+        .collect();
 
-        pool.execute(|| {
-            // Establishing the connection
-            handle_connection(stream);
+    // Creating a thread pool that keeps min_workers threads and grows up to workers under load
+    // When every worker is busy and the queue is full, new connections are turned away
+    // with a 503 instead of piling up
+    let pool = ThreadPool::builder()
+        .min_workers(config.min_workers)
+        .max_workers(config.workers)
+        .keep_alive(config.worker_idle_timeout)
+        .queue_capacity(config.queue_capacity)
+        .overflow_policy(OverflowPolicy::Reject)
+        .starvation_timeout(config.starvation_timeout)
+        .build()
+        .unwrap_or_else(|err| {
+            error!("Can't start the workers: {}", err);
+            process::exit(1);
         });
+
+    // Ctrl+C or SIGTERM will stop the server
+    let shutdown = Shutdown::new();
+    shutdown
+        .listen_for_signals()
+        .expect("Failed to install the signal handlers");
+
+    // Idle keep-alive connections are closed after a while so they don't hold a worker,
+    // and so are clients that send or read too slowly
+    let options = ConnectionOptions {
+        keep_alive_timeout: config.keep_alive_timeout,
+        header_timeout: config.header_timeout,
+        body_timeout: config.body_timeout,
+        write_timeout: config.write_timeout,
+        limits: Limits {
+            max_header_size: config.max_header_size,
+            max_body_size: config.max_body_size,
+        },
+        shutdown: shutdown.clone(),
+        access_log,
+        ..ConnectionOptions::default()
+    };
+
+    // Every worker needs to read the routes, so they live in an Arc with the rest
+    let server = Arc::new(Server {
+        router: routes(&config, pool.monitor()),
+        options: options.clone(),
+        config: config.clone(),
+        pool: pool.handle(),
+    });
+
+    // With tls.redirect the plain listeners only point to the HTTPS one
+    let plain_server = if config.tls_redirect {
+        let mut router = Router::new();
+        router.wrap(HttpsRedirect::new(config.tls_listen[0].port()));
+        Arc::new(Server {
+            router,
+            options,
+            config: config.clone(),
+            pool: pool.handle(),
+        })
+    } else {
+        Arc::clone(&server)
+    };
+
+    // Each listener gets its own accept loop, all of them share the pool
+    // thread::scope lets the threads borrow the pool, and waits for all of them at the end
+    thread::scope(|scope| {
+        for (listener, tls) in &listeners {
+            let encrypted = tls.is_some();
+            let scheme = if encrypted { "https" } else { "http" };
+            info!(
+                "Listening on {}://{}",
+                scheme,
+                listener.local_addr().unwrap()
+            );
+
+            let server = if encrypted { &server } else { &plain_server };
+            let (pool, shutdown) = (&pool, &shutdown);
+            scope.spawn(move || {
+                // We need to iterate over the incoming connections
+                // The iterator ends once a shutdown signal arrives
+                for stream in shutdown.incoming(listener) {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            // A failed accept (for example too many open files) shouldn't stop the server
+                            warn!("Failed to accept a connection: {}", err);
+                            continue;
+                        }
+                    };
+                    let server = Arc::clone(server);
+                    let tls = tls.clone();
+
+                    // We could create a new thread for each connection,
+                    // but this is not a good idea because it could lead to a DoS attack
+
+                    // The job owns the stream, so we keep a second handle to it
+                    // for answering when the pool refuses the job
+                    let overflow = match stream.try_clone() {
+                        Ok(overflow) => overflow,
+                        Err(err) => {
+                            warn!("Failed to accept a connection: {}", err);
+                            continue;
+                        }
+                    };
+
+                    // The first request of a connection is read in the high lane,
+                    // before we know its path there is nothing else to go by
+                    let queued = pool.try_execute_with_priority(Priority::High, move || {
+                        // Establishing the connection, the TLS handshake happens here in the worker
+                        let connection = match &tls {
+                            Some(tls) => Connection::with_tls(stream, tls, &server.options),
+                            None => Connection::new(stream, &server.options),
+                        };
+                        match connection {
+                            Ok(connection) => serve(server, connection, Priority::High, None),
+                            Err(err) => warn!("Connection error: {}", err),
+                        }
+                    });
+                    // A 503 over TLS would need a handshake on this thread first,
+                    // HTTPS clients just see the connection close
+                    if queued.is_err() && !encrypted {
+                        reject_connection(overflow);
+                    }
+                }
+            });
+        }
+    });
+
+    info!("Shutting down.");
+
+    // The workers get some time to finish what they are doing
+    if !pool.shutdown_timeout(config.shutdown_timeout) {
+        warn!("Some connections were still open, exiting anyway.");
     }
+}
 
-    println!("Shutting down.");
+// The default certificate and one more for each name in tls.sni
+fn load_tls(config: &Config) -> Result<Tls, server::TlsError> {
+    // Config makes sure both are there when there is an HTTPS listener
+    let (Some(certificate), Some(private_key)) = (&config.tls_certificate, &config.tls_private_key)
+    else {
+        unreachable!("HTTPS without a certificate");
+    };
+    let mut builder = Tls::builder(Certificate::load(certificate, private_key)?);
+    for (name, certificate, private_key) in &config.tls_sni {
+        builder = builder.sni(name, Certificate::load(certificate, private_key)?);
+    }
+    builder.build()
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    // We need to define the buffer to store the data
-    let mut buffer = [0; 512];
+// What the connection jobs share
+struct Server {
+    router: Router,
+    options: ConnectionOptions,
+    config: Config,
+    // Connections change jobs through this, a job can't own the pool
+    pool: PoolHandle,
+}
 
-    // We need to read the data from the stream
-    stream.read(&mut buffer).unwrap();
+// Serves `connection` in the lane of `lane`, starting with `pending` if it was already read
+//
+// The connection is reused for more requests until the client closes it. Each
+// request is answered with the priority of its path, when that is not the lane
+// we are in, the connection moves to a new job in the right one.
+fn serve(
+    server: Arc<Server>,
+    mut connection: Connection,
+    lane: Priority,
+    mut pending: Option<Request>,
+) {
+    loop {
+        let mut request = match pending.take() {
+            Some(request) => request,
+            None => match connection.read_request(&server.options) {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(err) => {
+                    warn!("Connection error: {}", err);
+                    return;
+                }
+            },
+        };
+
+        let priority = server.config.priority(&request.path);
+        if priority != lane {
+            // A job started by a job is always queued, so the connection can't get lost here
+            let pool = server.pool.clone();
+            pool.execute_with_priority(priority, move || {
+                serve(server, connection, priority, Some(request))
+            });
+            return;
+        }
+
+        match connection.respond(&mut request, &server.router, &server.options) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(err) => {
+                warn!("Connection error: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+// Adding an endpoint only means adding a line here
+fn routes(config: &Config, monitor: PoolMonitor) -> Router {
+    let document_root = config.document_root.as_path();
+    let mut router = Router::new();
+
+    // How long browsers may keep what they got, by path
+    let mut cache_control = CacheControl::new();
+    for (prefix, value) in &config.cache_control {
+        cache_control = cache_control.rule(prefix, value);
+    }
+    router.wrap(cache_control);
+
+    // Text goes out gzipped to the clients that can take it
+    if config.compression_level > 0 {
+        router.wrap(
+            Compression::new(config.compression_level).min_size(config.compression_min_size as u64),
+        );
+    }
+    let files = StaticFiles::new(document_root);
+
+    // The pool's stats for Prometheus to scrape
+    router.get("/metrics", move |_, _| {
+        Response::text(Status::Ok, monitor.stats().to_prometheus("server_pool"))
+            .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+    });
 
-    // We need to define the request
-    // b"GET / HTTP/1.1\r\n" is a byte string
-    let get = b"GET / HTTP/1.1\r\n";
     // This requests will sleep for 5 seconds to test the thread pool
     // This simmulates a slow request
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    // We need to check if the request is a GET request
-    let (status, filename) = if buffer.starts_with(get) {
-        // If the request is a GET request we need to call the response function
-        ("200 OK", "index.html")
-    } else if buffer.starts_with(sleep) {
-        // If it is a sleep request we need to sleep for 5 seconds
+    let root = document_root.to_path_buf();
+    router.get("/sleep", move |_, _| {
         thread::sleep(Duration::from_secs(5));
-        ("200 OK", "index.html")
-    } else {
-        // If the request is not a GET request we need to call the 404 function
-        ("404 NOT FOUND", "404.html")
-    };
+        file_response(&root, Status::Ok, "index.html")
+    });
+
+    // Everything else is a file from the document root, "/" serves index.html
+    let root = document_root.to_path_buf();
+    router.get("/*path", move |request, params| {
+        let response = files.serve(request, params.get("path").unwrap_or(""));
+        if response.status == Status::NotFound {
+            return not_found(&root);
+        }
+        response
+    });
 
+    let root = document_root.to_path_buf();
+    router.not_found(move |_, _| not_found(&root));
+
+    router
+}
+
+fn not_found(document_root: &Path) -> Response {
+    file_response(document_root, Status::NotFound, "404.html")
+}
+
+fn file_response(document_root: &Path, status: Status, filename: &str) -> Response {
     // Reading contents of the html file
-    let contents = fs::read_to_string(filename).unwrap();
-
-    // We need to define the response
-    // The format! macro is similar to println! but instead of printing the output to the console
-    // it returns a string with the formatted text
-    let response = format!(
-        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
-        // contents-length is the length of the contents in bytes
-        status,
-        contents.len(),
-        contents
-    );
-
-    // We need to write the response to the stream
-    stream.write(response.as_bytes()).unwrap();
-    // flush will wait and prevent the program from continuing until
-    // all the bytes are written to the connection
-    stream.flush().unwrap();
+    match fs::read(document_root.join(filename)) {
+        Ok(contents) => Response::html(status, contents),
+        Err(err) => {
+            error!("Failed to read {}: {}", filename, err);
+            Response::new(Status::InternalServerError)
+        }
+    }
 }
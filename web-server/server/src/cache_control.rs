@@ -0,0 +1,131 @@
+// Cache-Control headers by path prefix, as a middleware
+//
+// Which responses browsers and proxies may keep, and for how long, is a
+// property of the site more than of the handler: everything under /assets
+// never changes, /metrics must never be cached. So the rules are set in one
+// place and the middleware adds the header to the matching responses.
+
+use crate::config::matches_prefix;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{Response, Status};
+
+/// Adds a `Cache-Control` header to responses, picked by the path of the request
+///
+/// The rule with the longest matching prefix wins. Only successful answers
+/// and 304s get the header, an error page shouldn't be cached for a year, and
+/// a header the handler set itself is left alone.
+///
+/// ```
+/// use server::{CacheControl, Router};
+///
+/// let mut router = Router::new();
+/// router.wrap(
+///     CacheControl::new()
+///         .rule("/", "no-cache")
+///         .rule("/assets", "public, max-age=31536000, immutable"),
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CacheControl {
+    // Prefixes and their values, in the order they were added
+    rules: Vec<(String, String)>,
+}
+
+impl CacheControl {
+    pub fn new() -> CacheControl {
+        CacheControl::default()
+    }
+
+    /// Sends `value` for the requests under `prefix`, which matches whole segments
+    pub fn rule(mut self, prefix: &str, value: &str) -> CacheControl {
+        self.rules.push((prefix.to_string(), value.to_string()));
+        self
+    }
+
+    /// The header for `path`, if any rule matches it
+    pub fn value_for(&self, path: &str) -> Option<&str> {
+        // max_by_key returns the last of equals, so a rule added later wins a tie
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| matches_prefix(prefix, path))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Middleware for CacheControl {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let path = request.path.clone();
+        let mut response = next.run(request);
+
+        if matches!(
+            response.status,
+            Status::Ok | Status::PartialContent | Status::NotModified
+        ) && !response.headers.contains("Cache-Control")
+        {
+            if let Some(value) = self.value_for(&path) {
+                response.headers.insert("Cache-Control", value);
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+
+    fn get(router: &Router, path: &str) -> Response {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        router.handle(&mut Request::read_from(&mut raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let rules = CacheControl::new()
+            .rule("/", "no-cache")
+            .rule("/assets", "max-age=3600")
+            .rule("/assets/fonts/", "max-age=31536000");
+
+        assert_eq!(Some("no-cache"), rules.value_for("/index.html"));
+        assert_eq!(Some("max-age=3600"), rules.value_for("/assets/app.css"));
+        assert_eq!(
+            Some("max-age=31536000"),
+            rules.value_for("/assets/fonts/a.woff2")
+        );
+        // Whole segments only
+        assert_eq!(Some("no-cache"), rules.value_for("/assets-old/app.css"));
+        assert_eq!(None, CacheControl::new().rule("/a", "x").value_for("/b"));
+    }
+
+    #[test]
+    fn only_successful_responses_get_the_header() {
+        let mut router = Router::new();
+        router.wrap(
+            CacheControl::new()
+                .rule("/", "max-age=60")
+                .rule("/metrics", "no-store"),
+        );
+        router.get("/metrics", |_, _| Response::text(Status::Ok, "up 1"));
+        router.get("/page", |_, _| Response::text(Status::Ok, "page"));
+        router.get("/own", |_, _| {
+            Response::text(Status::Ok, "own").with_header("Cache-Control", "private")
+        });
+
+        assert_eq!(
+            Some("no-store"),
+            get(&router, "/metrics").headers.get("Cache-Control")
+        );
+        assert_eq!(
+            Some("max-age=60"),
+            get(&router, "/page").headers.get("Cache-Control")
+        );
+        assert_eq!(
+            Some("private"),
+            get(&router, "/own").headers.get("Cache-Control")
+        );
+        assert_eq!(None, get(&router, "/missing").headers.get("Cache-Control"));
+    }
+}
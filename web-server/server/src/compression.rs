@@ -0,0 +1,373 @@
+// Gzip and deflate for responses, as a middleware
+//
+// The client lists what it can decode in `Accept-Encoding`, with an optional
+// weight for each: "gzip, deflate;q=0.5, *;q=0". We pick the one it likes best,
+// compress the body and say so in `Content-Encoding`.
+//
+// Only text-like bodies are worth it. Images, video, archives and fonts are
+// compressed already, another round makes them slower and often bigger. Tiny
+// bodies aren't worth it either, the gzip header alone is 18 bytes.
+
+use std::io::{self, Read, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{Body, Response, Status};
+
+// Streamed bodies are compressed in memory, since Content-Length has to be
+// known before the body is sent. Bigger ones go out as they are.
+const MAX_STREAMED_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Compresses responses for clients that accept it, see `Router::wrap`
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    level: u32,
+    min_size: u64,
+}
+
+impl Compression {
+    /// `level` goes from 1 (fastest) to 9 (smallest)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `level` is not between 1 and 9
+    pub fn new(level: u32) -> Compression {
+        assert!(
+            (1..=9).contains(&level),
+            "compression level must be between 1 and 9"
+        );
+        Compression {
+            level,
+            min_size: 1024,
+        }
+    }
+
+    /// Bodies smaller than this are sent as they are, 1024 bytes by default
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    // Whether the response would be compressed for a client that asked for it
+    fn applies_to(&self, response: &Response) -> bool {
+        let len = response.body.len();
+        response.status.allows_body()
+            // Content-Range counts the bytes of the file, not of a compressed copy
+            && response.status != Status::PartialContent
+            && may_be_compressed(response)
+            // A chunked body may never end, it can't be compressed up front
+            && !response.body.is_chunked()
+            && len >= self.min_size
+            && (response.body.as_bytes().is_some() || len <= MAX_STREAMED_SIZE)
+    }
+
+    // Whether the response depends on Accept-Encoding
+    //
+    // A 304 has no body, but it stands for the 200 the client has in its
+    // cache, which was compressed or not depending on the same header.
+    fn varies(&self, response: &Response) -> bool {
+        match response.status {
+            Status::NotModified => may_be_compressed(response),
+            _ => self.applies_to(response),
+        }
+    }
+}
+
+// What the headers say about compressing, whatever the body
+fn may_be_compressed(response: &Response) -> bool {
+    !response.headers.contains("Content-Encoding")
+        && !response.headers.has_token("Cache-Control", "no-transform")
+        && response
+            .headers
+            .get("Content-Type")
+            .is_some_and(is_compressible)
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new(6)
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let accepted: Vec<String> = request
+            .headers
+            .get_all("Accept-Encoding")
+            .map(str::to_string)
+            .collect();
+        let mut response = next.run(request);
+
+        if !self.varies(&response) {
+            return response;
+        }
+        // From here on the body depends on Accept-Encoding, caches must know that
+        if !response.headers.has_token("Vary", "Accept-Encoding")
+            && !response.headers.has_token("Vary", "*")
+        {
+            match response.headers.get("Vary") {
+                Some(vary) => {
+                    let vary = format!("{}, Accept-Encoding", vary);
+                    response.headers.insert("Vary", &vary);
+                }
+                None => response.headers.insert("Vary", "Accept-Encoding"),
+            }
+        }
+
+        if !self.applies_to(&response) {
+            return response;
+        }
+        let Some(encoding) = negotiate(&accepted.join(",")) else {
+            return response;
+        };
+        match compress(&mut response.body, encoding, self.level) {
+            Ok(compressed) => {
+                response.body = Body::Bytes(compressed);
+                response
+                    .headers
+                    .insert("Content-Encoding", encoding.as_str());
+                // The bytes differ from the uncompressed ones, so a strong ETag would lie
+                if let Some(etag) = response.headers.get("ETag") {
+                    if !etag.starts_with("W/") {
+                        let weak = format!("W/{}", etag);
+                        response.headers.insert("ETag", &weak);
+                    }
+                }
+                response
+            }
+            Err(err) => {
+                // A streamed body is half read by now, there is nothing left to send
+                crate::error!("Failed to compress the response: {}", err);
+                Response::new(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    // The zlib format, which is what HTTP calls deflate
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+// The encoding the client prefers, None if it accepts neither
+//
+// Codings that are not listed get the weight of "*", or 0 without one. On a
+// tie gzip wins, every client that knows deflate knows gzip too.
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let (mut gzip, mut deflate, mut any) = (None, None, None);
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let mut weight = 1.0;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    // A weight we can't read counts as "not acceptable"
+                    weight = value.trim().parse().unwrap_or(0.0);
+                }
+            }
+        }
+
+        if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(weight);
+        } else if coding.eq_ignore_ascii_case("deflate") {
+            deflate = Some(weight);
+        } else if coding == "*" {
+            any = Some(weight);
+        }
+    }
+
+    let gzip: f32 = gzip.or(any).unwrap_or(0.0);
+    let deflate: f32 = deflate.or(any).unwrap_or(0.0);
+    if gzip <= 0.0 && deflate <= 0.0 {
+        None
+    } else if gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else {
+        Some(Encoding::Deflate)
+    }
+}
+
+// Media types that get smaller when compressed
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+        )
+}
+
+fn compress(body: &mut Body, encoding: Encoding, level: u32) -> io::Result<Vec<u8>> {
+    let level = flate2::Compression::new(level);
+    let out = Vec::with_capacity(body.len() as usize / 2);
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(out, level);
+            copy_body(body, &mut encoder)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(out, level);
+            copy_body(body, &mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn copy_body(body: &mut Body, out: &mut dyn Write) -> io::Result<()> {
+    match body {
+        Body::Bytes(bytes) => out.write_all(bytes),
+        Body::Stream { reader, len } => {
+            let copied = io::copy(&mut reader.take(*len), out)?;
+            if copied < *len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(())
+        }
+        Body::Chunked(_) => unreachable!("chunked bodies are not compressed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.wrap(Compression::default());
+        router.get("/text", |_, _| {
+            Response::text(Status::Ok, "hello compression ".repeat(100))
+        });
+        router.get("/short", |_, _| Response::text(Status::Ok, "hello"));
+        router.get("/cached/:kind", |_, params| {
+            let content_type = match params.get("kind") {
+                Some("text") => "text/html",
+                _ => "image/png",
+            };
+            Response::new(Status::NotModified).with_header("Content-Type", content_type)
+        });
+        router.get("/image", |_, _| {
+            Response::new(Status::Ok)
+                .with_header("Content-Type", "image/png")
+                .with_body(vec![0; 4096])
+        });
+        router.get("/file", |_, _| {
+            let text = "streamed ".repeat(500).into_bytes();
+            Response::text(
+                Status::Ok,
+                Body::Stream {
+                    len: text.len() as u64,
+                    reader: Box::new(io::Cursor::new(text)),
+                },
+            )
+            .with_header("Vary", "Origin")
+            .with_header("ETag", "\"v1\"")
+        });
+        router
+    }
+
+    fn get(router: &Router, path: &str, accept_encoding: Option<&str>) -> Response {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(accept_encoding) = accept_encoding {
+            raw.push_str(&format!("Accept-Encoding: {}\r\n", accept_encoding));
+        }
+        raw.push_str("\r\n");
+        router.handle(&mut Request::read_from(&mut raw.as_bytes()).unwrap())
+    }
+
+    fn decode(response: &Response, decoder: impl Fn(&[u8]) -> Box<dyn Read + '_>) -> String {
+        let mut text = String::new();
+        decoder(response.body.as_bytes().unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn negotiates_by_weight() {
+        assert_eq!(Some(Encoding::Gzip), negotiate("gzip, deflate"));
+        assert_eq!(Some(Encoding::Deflate), negotiate("gzip;q=0.5, deflate"));
+        assert_eq!(Some(Encoding::Deflate), negotiate("deflate, br"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("*"));
+        assert_eq!(Some(Encoding::Deflate), negotiate("gzip;q=0, *;q=0.1"));
+        assert_eq!(None, negotiate("br, identity"));
+        assert_eq!(None, negotiate("gzip;q=0"));
+        assert_eq!(None, negotiate(""));
+    }
+
+    #[test]
+    fn compresses_text_for_clients_that_ask() {
+        let router = router();
+
+        let response = get(&router, "/text", Some("gzip"));
+        assert_eq!(Some("gzip"), response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+        assert!(response.body.len() < 1800);
+        let text = decode(&response, |bytes| Box::new(GzDecoder::new(bytes)));
+        assert_eq!("hello compression ".repeat(100), text);
+
+        let response = get(&router, "/file", Some("deflate"));
+        assert_eq!(Some("deflate"), response.headers.get("Content-Encoding"));
+        assert_eq!(
+            Some("Origin, Accept-Encoding"),
+            response.headers.get("Vary")
+        );
+        assert_eq!(Some("W/\"v1\""), response.headers.get("ETag"));
+        let text = decode(&response, |bytes| Box::new(ZlibDecoder::new(bytes)));
+        assert_eq!("streamed ".repeat(500), text);
+    }
+
+    #[test]
+    fn leaves_the_rest_alone() {
+        let router = router();
+
+        // The client didn't ask, but the answer could have been different
+        let response = get(&router, "/text", None);
+        assert_eq!(None, response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+        assert_eq!(1800, response.body.len());
+
+        // Too small, or compressed already
+        for path in ["/short", "/image"] {
+            let response = get(&router, path, Some("gzip"));
+            assert_eq!(None, response.headers.get("Content-Encoding"));
+            assert_eq!(None, response.headers.get("Vary"));
+        }
+    }
+
+    #[test]
+    fn not_modified_varies_like_the_full_response() {
+        let router = router();
+
+        let response = get(&router, "/cached/text", Some("gzip"));
+        assert_eq!(Status::NotModified, response.status);
+        assert_eq!(None, response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+
+        let response = get(&router, "/cached/image", Some("gzip"));
+        assert_eq!(None, response.headers.get("Vary"));
+    }
+}
@@ -0,0 +1,1135 @@
+// Server configuration, read from a TOML file and overridden by command-line flags
+//
+// Only the part of TOML the config needs is supported: `[section]` headers,
+// `key = value` lines, comments, and values that are strings, integers,
+// booleans or single-line arrays.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::access_log::LogFormat;
+use crate::queue::Priority;
+
+/// Used when `--config` is not given, it's fine if it doesn't exist
+pub const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+pub const USAGE: &str = "\
+Usage: main [OPTIONS]
+
+Options:
+  --config <FILE>               TOML file to read (default: server.toml if it exists)
+  --listen <ADDRESS>            Address to listen on, can be repeated
+  --workers <N>                 Most worker threads to run at once
+  --min-workers <N>             Worker threads kept even when idle
+  --queue-capacity <N>          Connections that may wait for a worker before getting a 503
+  --document-root <DIR>         Directory the files are served from
+  --keep-alive-timeout <TIME>   Close idle connections after this long, like 5s or 500ms
+  --shutdown-timeout <TIME>     How long the workers get to finish when stopping
+  --worker-idle-timeout <TIME>  Stop extra workers that were idle this long
+  --header-timeout <TIME>       Answer 408 when a request's headers take longer than this
+  --body-timeout <TIME>         ...or when its body takes longer than this
+  --write-timeout <TIME>        Give up on a client that accepts no data for this long
+  --max-header-size <BYTES>     Answer 431 to requests with bigger headers
+  --max-body-size <BYTES>       Answer 413 to requests with bigger bodies
+  --compression-level <N>       Gzip level from 1 (fastest) to 9 (smallest), 0 turns it off
+  --compression-min-size <N>    Send responses smaller than N bytes uncompressed
+  --cache-control <RULE>        Like \"/assets=max-age=3600\", can be repeated
+  --high-priority <PATH>        Answer requests under PATH first, can be repeated
+  --low-priority <PATH>         Answer requests under PATH last, can be repeated
+  --log-level <LEVEL>           error, warn, info, debug or trace
+  --access-log <FILE>           Append a line per request to FILE, - for stdout
+  --access-log-format <FORMAT>  common, combined or json
+  --tls-listen <ADDRESS>        Address to serve HTTPS on, can be repeated
+  --tls-certificate <FILE>      PEM file with the certificate chain
+  --tls-private-key <FILE>      PEM file with its private key
+  --tls-sni <NAME=CERT,KEY>     Another certificate for clients that ask for NAME, can be repeated
+  --tls-redirect <BOOL>         Answer plain HTTP requests with a redirect to HTTPS
+  --help                        Print this message";
+
+// Command-line flags and the key they override in the file
+const FLAGS: &[(&str, &str)] = &[
+    ("--listen", "listen"),
+    ("--workers", "workers"),
+    ("--min-workers", "min_workers"),
+    ("--queue-capacity", "queue_capacity"),
+    ("--document-root", "document_root"),
+    ("--keep-alive-timeout", "timeouts.keep_alive"),
+    ("--shutdown-timeout", "timeouts.shutdown"),
+    ("--worker-idle-timeout", "timeouts.worker_idle"),
+    ("--header-timeout", "timeouts.header_read"),
+    ("--body-timeout", "timeouts.body_read"),
+    ("--write-timeout", "timeouts.write"),
+    ("--max-header-size", "limits.max_header_size"),
+    ("--max-body-size", "limits.max_body_size"),
+    ("--compression-level", "compression.level"),
+    ("--compression-min-size", "compression.min_size"),
+    ("--cache-control", "cache_control.rules"),
+    ("--log-level", "log_level"),
+    ("--high-priority", "priorities.high"),
+    ("--low-priority", "priorities.low"),
+    ("--access-log", "access_log.path"),
+    ("--access-log-format", "access_log.format"),
+    ("--tls-listen", "tls.listen"),
+    ("--tls-certificate", "tls.certificate"),
+    ("--tls-private-key", "tls.private_key"),
+    ("--tls-sni", "tls.sni"),
+    ("--tls-redirect", "tls.redirect"),
+];
+
+// Settings that are lists, their flags can be given several times
+const LISTS: &[&str] = &[
+    "listen",
+    "priorities.high",
+    "priorities.low",
+    "cache_control.rules",
+    "tls.listen",
+    "tls.sni",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(level: &str) -> Option<LogLevel> {
+        match level.to_ascii_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// The pool grows up to this many workers under load
+    pub workers: usize,
+    /// ...and shrinks back to this many when it is quiet
+    pub min_workers: usize,
+    pub queue_capacity: usize,
+    pub document_root: PathBuf,
+    pub keep_alive_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub worker_idle_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    /// Bytes in the request line and headers of one request
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    /// 1 to 9, 0 means responses are never compressed
+    pub compression_level: u32,
+    /// Smaller responses are sent as they are
+    pub compression_min_size: usize,
+    /// Path prefixes and the `Cache-Control` header for the responses under them
+    pub cache_control: Vec<(String, String)>,
+    pub log_level: LogLevel,
+    /// Path prefixes whose requests go ahead of the others
+    pub high_priority: Vec<String>,
+    /// Path prefixes whose requests wait until nothing else does
+    pub low_priority: Vec<String>,
+    /// How long requests may be passed over by higher priority ones
+    pub starvation_timeout: Duration,
+    /// Where to record the requests, nowhere if `None`
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: LogFormat,
+    /// Addresses to serve HTTPS on, none means no HTTPS
+    pub tls_listen: Vec<SocketAddr>,
+    /// PEM files with the certificate chain and its key, for clients that
+    /// ask for no name in particular
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_private_key: Option<PathBuf>,
+    /// Names and the certificate and key files for them
+    pub tls_sni: Vec<(String, PathBuf, PathBuf)>,
+    /// Whether the plain listeners only redirect to HTTPS
+    pub tls_redirect: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            workers: 4,
+            min_workers: 1,
+            queue_capacity: 64,
+            document_root: PathBuf::from("public"),
+            keep_alive_timeout: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(10),
+            worker_idle_timeout: Duration::from_secs(60),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+            compression_level: 6,
+            compression_min_size: 1024,
+            cache_control: Vec::new(),
+            log_level: LogLevel::Info,
+            high_priority: Vec::new(),
+            low_priority: Vec::new(),
+            starvation_timeout: Duration::from_secs(1),
+            access_log: None,
+            access_log_format: LogFormat::Combined,
+            tls_listen: Vec::new(),
+            tls_certificate: None,
+            tls_private_key: None,
+            tls_sni: Vec::new(),
+            tls_redirect: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was passed, the caller should print `USAGE` and exit
+    HelpRequested,
+    /// Something is wrong with the command line
+    Usage(String),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The file is not valid TOML
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    /// A setting has a value that doesn't make sense
+    Invalid {
+        origin: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "{}", USAGE),
+            ConfigError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            ConfigError::Io { path, error } => {
+                write!(f, "can't read {}: {}", path.display(), error)
+            }
+            ConfigError::Syntax {
+                path,
+                line,
+                message,
+            } => write!(f, "{}, line {}: {}", path.display(), line, message),
+            ConfigError::Invalid { origin, message } => write!(f, "{}: {}", origin, message),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A value from the file or from a flag
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+// A value and where it came from, so errors can point at the right line or flag
+#[derive(Debug, Clone)]
+struct Setting {
+    value: Value,
+    origin: String,
+}
+
+impl Config {
+    /// Builds the configuration from the command-line arguments
+    ///
+    /// Like `env::args()`, the first item is expected to be the program name.
+    /// The file named by `--config` is read first and the other flags override it.
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, ConfigError> {
+        args.next(); // skip the first value, which is the name of the program
+
+        let mut config_file = None;
+        let mut overrides: Vec<(&str, String, String)> = Vec::new();
+
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                return Err(ConfigError::HelpRequested);
+            }
+
+            // Both "--workers 8" and "--workers=8" are accepted
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(ConfigError::Usage(format!("{} needs a value", flag))),
+            };
+
+            if flag == "--config" {
+                config_file = Some(PathBuf::from(value));
+                continue;
+            }
+
+            match FLAGS.iter().find(|(name, _)| *name == flag) {
+                Some((_, key)) => overrides.push((key, flag, value)),
+                None => return Err(ConfigError::Usage(format!("unknown option {}", flag))),
+            }
+        }
+
+        let mut table = match config_file {
+            Some(path) => read_file(&path)?,
+            // The default file is optional, a missing one just means defaults
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => HashMap::new(),
+        };
+
+        for (key, flag, value) in overrides {
+            let value = Value::String(value);
+            let setting = table.entry(key.to_string()).or_insert(Setting {
+                value: Value::Array(Vec::new()),
+                origin: flag.clone(),
+            });
+
+            // List flags can be given several times, the other flags replace the value
+            let is_list = LISTS.contains(&key);
+            if is_list && setting.origin == flag {
+                if let Value::Array(values) = &mut setting.value {
+                    values.push(value);
+                    continue;
+                }
+            }
+            *setting = Setting {
+                value: if is_list {
+                    Value::Array(vec![value])
+                } else {
+                    value
+                },
+                origin: flag,
+            };
+        }
+
+        Config::from_table(table)
+    }
+
+    /// Reads and validates a configuration file, without command-line overrides
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        Config::from_table(read_file(path)?)
+    }
+
+    /// Parses and validates configuration text, `name` is used in error messages
+    pub fn parse(name: &str, text: &str) -> Result<Config, ConfigError> {
+        Config::from_table(parse_table(Path::new(name), text)?)
+    }
+
+    // Turns the raw settings into a Config, checking every value on the way
+    fn from_table(mut table: HashMap<String, Setting>) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+
+        if let Some(setting) = table.remove("listen") {
+            config.listen = addresses(&setting)?;
+            if config.listen.is_empty() {
+                return Err(invalid(&setting, "at least one address is needed"));
+            }
+        }
+
+        if let Some(setting) = table.remove("workers") {
+            config.workers = positive_integer(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("min_workers") {
+            config.min_workers = non_negative_integer(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("queue_capacity") {
+            config.queue_capacity = positive_integer(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("document_root") {
+            config.document_root = PathBuf::from(string(&setting)?);
+        }
+
+        if let Some(setting) = table.remove("timeouts.keep_alive") {
+            config.keep_alive_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("timeouts.shutdown") {
+            config.shutdown_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("timeouts.worker_idle") {
+            config.worker_idle_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("timeouts.header_read") {
+            config.header_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("timeouts.body_read") {
+            config.body_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("timeouts.write") {
+            config.write_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("limits.max_header_size") {
+            config.max_header_size = positive_integer(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("limits.max_body_size") {
+            config.max_body_size = non_negative_integer(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("compression.level") {
+            let level = non_negative_integer(&setting)?;
+            if level > 9 {
+                return Err(invalid(&setting, "must be between 0 and 9"));
+            }
+            config.compression_level = level as u32;
+        }
+
+        if let Some(setting) = table.remove("compression.min_size") {
+            config.compression_min_size = non_negative_integer(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("cache_control.rules") {
+            config.cache_control = cache_rules(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("log_level") {
+            let level = string(&setting)?;
+            config.log_level = LogLevel::parse(&level).ok_or_else(|| {
+                invalid(
+                    &setting,
+                    format!(
+                        "unknown log level {:?}, expected error, warn, info, debug or trace",
+                        level
+                    ),
+                )
+            })?;
+        }
+
+        if let Some(setting) = table.remove("priorities.high") {
+            config.high_priority = path_prefixes(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("priorities.low") {
+            config.low_priority = path_prefixes(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("priorities.starvation_timeout") {
+            config.starvation_timeout = duration(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("access_log.path") {
+            config.access_log = Some(PathBuf::from(string(&setting)?));
+        }
+
+        if let Some(setting) = table.remove("access_log.format") {
+            let format = string(&setting)?;
+            config.access_log_format = LogFormat::parse(&format).ok_or_else(|| {
+                invalid(
+                    &setting,
+                    format!(
+                        "unknown access log format {:?}, expected common, combined or json",
+                        format
+                    ),
+                )
+            })?;
+        }
+
+        if let Some(setting) = table.remove("tls.listen") {
+            config.tls_listen = addresses(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("tls.certificate") {
+            config.tls_certificate = Some(PathBuf::from(string(&setting)?));
+        }
+
+        if let Some(setting) = table.remove("tls.private_key") {
+            config.tls_private_key = Some(PathBuf::from(string(&setting)?));
+        }
+
+        if let Some(setting) = table.remove("tls.sni") {
+            config.tls_sni = sni_certificates(&setting)?;
+        }
+
+        if let Some(setting) = table.remove("tls.redirect") {
+            config.tls_redirect = boolean(&setting)?;
+        }
+
+        // Anything left is a typo or a setting this version doesn't know
+        if let Some((key, setting)) = table.iter().next() {
+            return Err(invalid(setting, format!("unknown setting `{}`", key)));
+        }
+
+        if config.min_workers > config.workers {
+            return Err(ConfigError::Invalid {
+                origin: "min_workers".to_string(),
+                message: format!(
+                    "{} is more than the {} workers allowed",
+                    config.min_workers, config.workers
+                ),
+            });
+        }
+
+        if !config.tls_listen.is_empty()
+            && (config.tls_certificate.is_none() || config.tls_private_key.is_none())
+        {
+            return Err(ConfigError::Invalid {
+                origin: "tls.listen".to_string(),
+                message: "HTTPS needs tls.certificate and tls.private_key".to_string(),
+            });
+        }
+
+        if config.tls_redirect && config.tls_listen.is_empty() {
+            return Err(ConfigError::Invalid {
+                origin: "tls.redirect".to_string(),
+                message: "there is no HTTPS listener to redirect to".to_string(),
+            });
+        }
+
+        if !config.document_root.is_dir() {
+            return Err(ConfigError::Invalid {
+                origin: "document_root".to_string(),
+                message: format!("{} is not a directory", config.document_root.display()),
+            });
+        }
+
+        Ok(config)
+    }
+
+    /// The priority of requests for `path`
+    ///
+    /// A prefix matches whole segments, "/admin" matches "/admin/users" but not
+    /// "/administrator". When prefixes from both lists match, the longest wins.
+    pub fn priority(&self, path: &str) -> Priority {
+        let high = longest_match(&self.high_priority, path);
+        let low = longest_match(&self.low_priority, path);
+        match (high, low) {
+            (Some(high), Some(low)) if low > high => Priority::Low,
+            (Some(_), _) => Priority::High,
+            (None, Some(_)) => Priority::Low,
+            (None, None) => Priority::Normal,
+        }
+    }
+}
+
+// The length of the longest prefix in `prefixes` that matches `path`
+fn longest_match(prefixes: &[String], path: &str) -> Option<usize> {
+    prefixes
+        .iter()
+        .filter(|prefix| matches_prefix(prefix, path))
+        .map(|prefix| prefix.len())
+        .max()
+}
+
+// Whether `path` is `prefix` or under it, "/admin" is a prefix of "/admin/users"
+// but not of "/administrator"
+pub(crate) fn matches_prefix(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+fn invalid(setting: &Setting, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        origin: setting.origin.clone(),
+        message: message.into(),
+    }
+}
+
+fn string(setting: &Setting) -> Result<String, ConfigError> {
+    match &setting.value {
+        Value::String(value) => Ok(value.clone()),
+        _ => Err(invalid(setting, "expected a string")),
+    }
+}
+
+fn string_list(setting: &Setting) -> Result<Vec<String>, ConfigError> {
+    match &setting.value {
+        // A single string is a list with one item
+        Value::String(value) => Ok(vec![value.clone()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => Ok(value.clone()),
+                _ => Err(invalid(setting, "expected a list of strings")),
+            })
+            .collect(),
+        _ => Err(invalid(setting, "expected a string or a list of strings")),
+    }
+}
+
+fn path_prefixes(setting: &Setting) -> Result<Vec<String>, ConfigError> {
+    let prefixes = string_list(setting)?;
+    if let Some(prefix) = prefixes.iter().find(|prefix| !prefix.starts_with('/')) {
+        return Err(invalid(
+            setting,
+            format!("{:?} is not a path, it should start with /", prefix),
+        ));
+    }
+    Ok(prefixes)
+}
+
+// "/assets = public, max-age=3600" -> ("/assets", "public, max-age=3600")
+fn cache_rules(setting: &Setting) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut rules = Vec::new();
+    for rule in string_list(setting)? {
+        let (prefix, value) = match rule.split_once('=') {
+            Some((prefix, value)) if !value.trim().is_empty() => (prefix.trim(), value.trim()),
+            _ => {
+                return Err(invalid(
+                    setting,
+                    format!("{:?} should look like \"/path = max-age=60\"", rule),
+                ))
+            }
+        };
+        if !prefix.starts_with('/') {
+            return Err(invalid(
+                setting,
+                format!("{:?} is not a path, it should start with /", prefix),
+            ));
+        }
+        rules.push((prefix.to_string(), value.to_string()));
+    }
+    Ok(rules)
+}
+
+fn addresses(setting: &Setting) -> Result<Vec<SocketAddr>, ConfigError> {
+    let mut addresses = Vec::new();
+    for address in string_list(setting)? {
+        let resolved = address
+            .to_socket_addrs()
+            .map_err(|err| invalid(setting, format!("bad address {:?}: {}", address, err)))?;
+        addresses.extend(resolved);
+    }
+    Ok(addresses)
+}
+
+// "example.org = example.org.pem, example.org.key" for each name
+fn sni_certificates(setting: &Setting) -> Result<Vec<(String, PathBuf, PathBuf)>, ConfigError> {
+    let mut certificates = Vec::new();
+    for entry in string_list(setting)? {
+        let parsed = entry.split_once('=').and_then(|(name, files)| {
+            let (certificate, key) = files.split_once(',')?;
+            let (name, certificate, key) = (name.trim(), certificate.trim(), key.trim());
+            if name.is_empty() || certificate.is_empty() || key.is_empty() {
+                return None;
+            }
+            Some((
+                name.to_string(),
+                PathBuf::from(certificate),
+                PathBuf::from(key),
+            ))
+        });
+        match parsed {
+            Some(certificate) => certificates.push(certificate),
+            None => {
+                return Err(invalid(
+                    setting,
+                    format!(
+                        "{:?} should look like \"example.org = cert.pem, key.pem\"",
+                        entry
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(certificates)
+}
+
+fn boolean(setting: &Setting) -> Result<bool, ConfigError> {
+    // Values from flags are always strings, so both forms are accepted
+    match &setting.value {
+        Value::Boolean(value) => Ok(*value),
+        Value::String(text) if text == "true" => Ok(true),
+        Value::String(text) if text == "false" => Ok(false),
+        _ => Err(invalid(setting, "expected true or false")),
+    }
+}
+
+fn integer(setting: &Setting) -> Result<i64, ConfigError> {
+    // Values from flags are always strings, so both forms are accepted
+    match &setting.value {
+        Value::Integer(number) => Ok(*number),
+        Value::String(text) => text
+            .parse()
+            .map_err(|_| invalid(setting, format!("{:?} is not a number", text))),
+        _ => Err(invalid(setting, "expected a number")),
+    }
+}
+
+fn positive_integer(setting: &Setting) -> Result<usize, ConfigError> {
+    let number = integer(setting)?;
+    if number <= 0 {
+        return Err(invalid(setting, "must be greater than 0"));
+    }
+    Ok(number as usize)
+}
+
+fn non_negative_integer(setting: &Setting) -> Result<usize, ConfigError> {
+    let number = integer(setting)?;
+    if number < 0 {
+        return Err(invalid(setting, "can't be negative"));
+    }
+    Ok(number as usize)
+}
+
+fn duration(setting: &Setting) -> Result<Duration, ConfigError> {
+    let text = string(setting)?;
+    match parse_duration(&text) {
+        Some(duration) if !duration.is_zero() => Ok(duration),
+        Some(_) => Err(invalid(setting, "must be greater than 0")),
+        None => Err(invalid(
+            setting,
+            format!(
+                "{:?} is not a duration, use something like \"5s\" or \"500ms\"",
+                text
+            ),
+        )),
+    }
+}
+
+/// Parses durations like "500ms", "5s", "2m" or "1h"
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().ok()?;
+
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(number.checked_mul(3600)?)),
+        _ => None,
+    }
+}
+
+fn read_file(path: &Path) -> Result<HashMap<String, Setting>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    parse_table(path, &text)
+}
+
+// Parses the file into "section.key" -> value
+fn parse_table(path: &Path, text: &str) -> Result<HashMap<String, Setting>, ConfigError> {
+    let mut table = HashMap::new();
+    let mut section = String::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let syntax = |message: &str| ConfigError::Syntax {
+            path: path.to_path_buf(),
+            line: number,
+            message: message.to_string(),
+        };
+
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| syntax("expected `]` at the end of the section name"))?
+                .trim();
+            if !is_valid_key(name) {
+                return Err(syntax("invalid section name"));
+            }
+            section = name.to_string();
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| syntax("expected `key = value`"))?;
+        let key = key.trim();
+        if !is_valid_key(key) {
+            return Err(syntax("invalid key"));
+        }
+
+        let value = parse_value(value.trim()).map_err(|message| syntax(&message))?;
+
+        let full_key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", section, key)
+        };
+        let setting = Setting {
+            value,
+            origin: format!("{}, line {}", path.display(), number),
+        };
+        if table.insert(full_key, setting).is_some() {
+            return Err(syntax("duplicate key"));
+        }
+    }
+
+    Ok(table)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+// Removes a "# comment", ignoring any # that is inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let (value, rest) = parse_value_prefix(text)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected {:?} after the value", rest.trim()));
+    }
+    Ok(value)
+}
+
+// Parses one value from the start of `text` and returns what is left after it
+fn parse_value_prefix(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\\')) => value.push('\\'),
+                    _ => return Err("invalid escape in string".to_string()),
+                },
+                _ => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value_prefix(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            // Items are separated by commas, a trailing comma is fine
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected `,` or `]` in array".to_string());
+            }
+        }
+    }
+
+    // Bare words end at the first separator
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => match word.replace('_', "").parse::<i64>() {
+            Ok(number) => Value::Integer(number),
+            Err(_) if word.is_empty() => return Err("missing value".to_string()),
+            Err(_) => return Err(format!("invalid value {:?}, strings need quotes", word)),
+        },
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> {
+        let mut all = vec!["main".to_string()];
+        all.extend(list.iter().map(|arg| arg.to_string()));
+        all.into_iter()
+    }
+
+    fn error_message(result: Result<Config, ConfigError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_full_file() {
+        let config = Config::parse(
+            "test.toml",
+            r#"
+            # Where to listen
+            listen = ["127.0.0.1:8080", "127.0.0.1:8081",]
+            workers = 8
+            min_workers = 0
+            queue_capacity = 16
+            document_root = "src" # any directory that exists will do
+            log_level = "debug"
+
+            [timeouts]
+            keep_alive = "500ms"
+            shutdown = "1m"
+            worker_idle = "30s"
+            header_read = "2s"
+            body_read = "20s"
+            write = "15s"
+
+            [limits]
+            max_header_size = 4096
+            max_body_size = 0
+
+            [compression]
+            level = 9
+            min_size = 256
+
+            [cache_control]
+            rules = ["/ = no-cache", "/assets = public, max-age=31536000"]
+
+            [priorities]
+            high = ["/metrics", "/admin"]
+            low = "/sleep"
+            starvation_timeout = "250ms"
+
+            [access_log]
+            path = "access.log"
+            format = "json"
+
+            [tls]
+            listen = "127.0.0.1:8443"
+            certificate = "site.pem"
+            private_key = "site.key"
+            sni = ["blog.example.org = blog.pem, blog.key"]
+            redirect = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                SocketAddr::from(([127, 0, 0, 1], 8081))
+            ],
+            config.listen
+        );
+        assert_eq!(8, config.workers);
+        assert_eq!(0, config.min_workers);
+        assert_eq!(16, config.queue_capacity);
+        assert_eq!(PathBuf::from("src"), config.document_root);
+        assert_eq!(LogLevel::Debug, config.log_level);
+        assert_eq!(Duration::from_millis(500), config.keep_alive_timeout);
+        assert_eq!(Duration::from_secs(60), config.shutdown_timeout);
+        assert_eq!(Duration::from_secs(30), config.worker_idle_timeout);
+        assert_eq!(Duration::from_secs(2), config.header_timeout);
+        assert_eq!(Duration::from_secs(20), config.body_timeout);
+        assert_eq!(Duration::from_secs(15), config.write_timeout);
+        assert_eq!(4096, config.max_header_size);
+        assert_eq!(0, config.max_body_size);
+        assert_eq!(9, config.compression_level);
+        assert_eq!(256, config.compression_min_size);
+        assert_eq!(
+            vec![
+                ("/".to_string(), "no-cache".to_string()),
+                (
+                    "/assets".to_string(),
+                    "public, max-age=31536000".to_string()
+                )
+            ],
+            config.cache_control
+        );
+        assert_eq!(vec!["/metrics", "/admin"], config.high_priority);
+        assert_eq!(vec!["/sleep"], config.low_priority);
+        assert_eq!(Duration::from_millis(250), config.starvation_timeout);
+        assert_eq!(Some(PathBuf::from("access.log")), config.access_log);
+        assert_eq!(LogFormat::Json, config.access_log_format);
+        assert_eq!(
+            vec![SocketAddr::from(([127, 0, 0, 1], 8443))],
+            config.tls_listen
+        );
+        assert_eq!(Some(PathBuf::from("site.pem")), config.tls_certificate);
+        assert_eq!(Some(PathBuf::from("site.key")), config.tls_private_key);
+        assert_eq!(
+            vec![(
+                "blog.example.org".to_string(),
+                PathBuf::from("blog.pem"),
+                PathBuf::from("blog.key")
+            )],
+            config.tls_sni
+        );
+        assert!(config.tls_redirect);
+    }
+
+    #[test]
+    fn priorities_follow_the_longest_prefix() {
+        let config = Config {
+            high_priority: vec!["/admin".to_string(), "/health".to_string()],
+            low_priority: vec!["/admin/reports/".to_string(), "/".to_string()],
+            ..Config::default()
+        };
+
+        assert_eq!(Priority::High, config.priority("/health"));
+        assert_eq!(Priority::High, config.priority("/admin/users"));
+        assert_eq!(Priority::Low, config.priority("/admin/reports/2024"));
+        // "/" matches everything, so everything else is low
+        assert_eq!(Priority::Low, config.priority("/healthz"));
+        assert_eq!(Priority::Normal, Config::default().priority("/healthz"));
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let dir = std::env::temp_dir().join(format!("server-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("server.toml");
+        fs::write(&file, "workers = 2\ndocument_root = \"src\"\n").unwrap();
+
+        let config = Config::from_args(args(&[
+            "--config",
+            file.to_str().unwrap(),
+            "--workers=16",
+            "--listen",
+            "127.0.0.1:1",
+            "--listen",
+            "127.0.0.1:2",
+            "--high-priority",
+            "/metrics",
+            "--high-priority=/health",
+            "--access-log",
+            "-",
+            "--access-log-format",
+            "common",
+        ]))
+        .unwrap();
+
+        assert_eq!(16, config.workers);
+        assert_eq!(PathBuf::from("src"), config.document_root);
+        assert_eq!(2, config.listen.len());
+        assert_eq!(vec!["/metrics", "/health"], config.high_priority);
+        assert_eq!(Some(PathBuf::from("-")), config.access_log);
+        assert_eq!(LogFormat::Common, config.access_log_format);
+    }
+
+    #[test]
+    fn reports_where_the_problem_is() {
+        assert_eq!(
+            "test.toml, line 2: expected `key = value`",
+            error_message(Config::parse("test.toml", "workers = 1\nworkers\n"))
+        );
+        assert_eq!(
+            "test.toml, line 1: must be greater than 0",
+            error_message(Config::parse("test.toml", "workers = 0"))
+        );
+        assert_eq!(
+            "test.toml, line 1: unknown setting `wrokers`",
+            error_message(Config::parse("test.toml", "wrokers = 4"))
+        );
+        assert_eq!(
+            "test.toml, line 2: duplicate key",
+            error_message(Config::parse("test.toml", "workers = 1\nworkers = 2"))
+        );
+        assert_eq!(
+            "min_workers: 8 is more than the 4 workers allowed",
+            error_message(Config::parse("t", "workers = 4\nmin_workers = 8"))
+        );
+        assert!(
+            error_message(Config::parse("t", "[timeouts]\nkeep_alive = \"soon\""))
+                .contains("is not a duration")
+        );
+        assert!(
+            error_message(Config::parse("t", "[priorities]\nhigh = [\"metrics\"]"))
+                .contains("\"metrics\" is not a path")
+        );
+        assert!(
+            error_message(Config::parse("t", "[cache_control]\nrules = [\"/a\"]"))
+                .contains("should look like")
+        );
+        assert_eq!(
+            "t, line 2: must be between 0 and 9",
+            error_message(Config::parse("t", "[compression]\nlevel = 10"))
+        );
+        assert!(
+            error_message(Config::parse("t", "[access_log]\nformat = \"xml\""))
+                .contains("unknown access log format \"xml\"")
+        );
+        assert!(
+            error_message(Config::parse("t", "document_root = \"no/such/dir\""))
+                .contains("is not a directory")
+        );
+        assert!(
+            error_message(Config::from_args(args(&["--workers", "many"])))
+                .starts_with("--workers: \"many\" is not a number")
+        );
+        assert!(error_message(Config::from_args(args(&["--verbose", "1"])))
+            .starts_with("unknown option --verbose"));
+        assert_eq!(
+            "tls.listen: HTTPS needs tls.certificate and tls.private_key",
+            error_message(Config::parse("t", "[tls]\nlisten = \"127.0.0.1:8443\""))
+        );
+        assert_eq!(
+            "tls.redirect: there is no HTTPS listener to redirect to",
+            error_message(Config::from_args(args(&["--tls-redirect", "true"])))
+        );
+        assert!(
+            error_message(Config::parse("t", "[tls]\nsni = [\"a.org = a.pem\"]"))
+                .contains("should look like")
+        );
+        assert!(matches!(
+            Config::from_args(args(&["--help"])),
+            Err(ConfigError::HelpRequested)
+        ));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(Some(Duration::from_millis(250)), parse_duration("250ms"));
+        assert_eq!(Some(Duration::from_secs(7200)), parse_duration("2h"));
+        assert_eq!(None, parse_duration("5"));
+        assert_eq!(None, parse_duration("s"));
+        assert_eq!(None, parse_duration("5 days"));
+    }
+}
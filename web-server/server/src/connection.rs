@@ -0,0 +1,426 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{self, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::{AccessLog, AccessRecord};
+
+use crate::request::{content_length, Limits, Method, ParseError, Request, Version};
+use crate::response::{Response, Status};
+use crate::router::Router;
+use crate::shutdown::Shutdown;
+use crate::tls::{Tls, TlsStream};
+
+/// How long a connection may stay open and how much it may be used
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    /// How long to wait for the next request before closing an idle connection
+    pub keep_alive_timeout: Duration,
+    /// How long the request line and headers may take once the first byte arrived
+    pub header_timeout: Duration,
+    /// How long the body may take after the headers
+    pub body_timeout: Duration,
+    /// How long a single write of the answer may block
+    pub write_timeout: Duration,
+    /// Bigger requests get a 431 or a 413
+    pub limits: Limits,
+    /// How many requests one connection may send before it is closed,
+    /// so a single client can't keep a worker busy forever
+    pub max_requests: usize,
+    /// Once this is triggered, connections close after the request they are serving
+    pub shutdown: Shutdown,
+    /// Where answered requests are recorded, if anywhere
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            keep_alive_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            limits: Limits::default(),
+            max_requests: 100,
+            shutdown: Shutdown::new(),
+            access_log: None,
+        }
+    }
+}
+
+/// Serves requests from `stream` until the client or the server closes it
+///
+/// HTTP/1.1 connections are persistent by default, so the same stream is used
+/// for as many requests as the client sends. Pipelined requests (sent before
+/// the previous answer arrived) are answered in order, since the reader keeps
+/// whatever was already buffered between iterations.
+pub fn handle_connection(stream: TcpStream, router: &Router, options: &ConnectionOptions) {
+    if let Err(err) = Connection::new(stream, options).and_then(|c| serve(c, router, options)) {
+        crate::warn!("Connection error: {}", err);
+    }
+}
+
+/// Like `handle_connection`, for a client that starts with a TLS handshake
+pub fn handle_tls_connection(
+    stream: TcpStream,
+    tls: &Tls,
+    router: &Router,
+    options: &ConnectionOptions,
+) {
+    if let Err(err) =
+        Connection::with_tls(stream, tls, options).and_then(|c| serve(c, router, options))
+    {
+        crate::warn!("Connection error: {}", err);
+    }
+}
+
+/// Answers `503 Service Unavailable` and closes the connection
+///
+/// Used when the pool has no room for another connection. This runs on the
+/// accept thread, so the write gets a short timeout to keep a slow client
+/// from holding up everyone else.
+pub fn reject_connection(stream: TcpStream) {
+    if let Err(err) = reject(&stream) {
+        crate::warn!("Connection error: {}", err);
+    }
+}
+
+fn reject(mut stream: &TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    Response::text(
+        Status::ServiceUnavailable,
+        "The server is busy, try again later",
+    )
+    .with_header("Retry-After", "1")
+    .with_header("Connection", "close")
+    .write_to(&mut stream)?;
+    linger(stream)
+}
+
+// Closing a socket with unread data makes the OS reset the connection,
+// and the client may lose the answer. So we say we are done writing
+// and read away what the client already sent, for a moment at most.
+fn linger(mut stream: &TcpStream) -> io::Result<()> {
+    stream.shutdown(net::Shutdown::Write)?;
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut buffer = [0; 4096];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(_) => continue,
+            Err(err) if is_timeout(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn serve(
+    mut connection: Connection,
+    router: &Router,
+    options: &ConnectionOptions,
+) -> io::Result<()> {
+    while let Some(mut request) = connection.read_request(options)? {
+        if !connection.respond(&mut request, router, options)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// An open connection, between reading a request and answering it
+///
+/// `handle_connection` does both on the calling thread. With a Connection the
+/// two halves can run in different jobs: the server reads a request, and then
+/// moves the connection to a job with the priority of the request's path.
+pub struct Connection {
+    // The reader owns the stream, answers are written through get_ref
+    reader: BufReader<TimedStream>,
+    served: usize,
+    // For the access log
+    client: Option<SocketAddr>,
+    // When the last request was read, by the clock and by the calendar
+    started: Instant,
+    arrived: SystemTime,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, options: &ConnectionOptions) -> io::Result<Connection> {
+        Connection::open(Stream::Plain(stream), options)
+    }
+
+    /// A connection that is encrypted with `tls`
+    ///
+    /// The handshake happens while the first request is read, in the worker,
+    /// and has the keep-alive timeout to finish.
+    pub fn with_tls(
+        stream: TcpStream,
+        tls: &Tls,
+        options: &ConnectionOptions,
+    ) -> io::Result<Connection> {
+        Connection::open(Stream::Tls(Box::new(tls.accept(stream)?)), options)
+    }
+
+    fn open(stream: Stream, options: &ConnectionOptions) -> io::Result<Connection> {
+        // A client that stops reading can't keep the worker stuck in a write
+        stream
+            .tcp()
+            .set_write_timeout(Some(options.write_timeout))?;
+        Ok(Connection {
+            client: stream.tcp().peer_addr().ok(),
+            reader: BufReader::new(TimedStream {
+                stream,
+                deadline: Instant::now() + options.keep_alive_timeout,
+            }),
+            served: 0,
+            started: Instant::now(),
+            arrived: SystemTime::now(),
+        })
+    }
+
+    /// Waits for the next request, `None` means the connection is done
+    ///
+    /// That is when the client closed it, when nothing arrived before the
+    /// keep-alive timeout, or when the request could not be read, which was
+    /// already answered: 408 if it was too slow, 431 or 413 if it was too big,
+    /// 501 for a transfer encoding we can't decode and 400 if it was malformed.
+    pub fn read_request(&mut self, options: &ConnectionOptions) -> io::Result<Option<Request>> {
+        // Nothing arriving before the keep-alive timeout is fine, we just hang up
+        self.reader.get_mut().deadline = Instant::now() + options.keep_alive_timeout;
+        match self.reader.fill_buf() {
+            // The client closed the connection between two requests
+            Ok([]) => return Ok(None),
+            Ok(_) => {}
+            Err(err) if is_timeout(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let result = self.read_parts(options);
+        self.started = Instant::now();
+        self.arrived = SystemTime::now();
+
+        let (status, message) = match result {
+            Ok(request) => return Ok(Some(request)),
+            Err(ParseError::ConnectionClosed) => return Ok(None),
+            Err(ParseError::Io(err)) if is_timeout(&err) => {
+                (Status::RequestTimeout, "request took too long".to_string())
+            }
+            Err(ParseError::Io(err)) => return Err(err),
+            Err(err @ ParseError::HeadersTooLarge) => {
+                (Status::RequestHeaderFieldsTooLarge, err.to_string())
+            }
+            Err(err @ ParseError::BodyTooLarge) => (Status::PayloadTooLarge, err.to_string()),
+            Err(err @ ParseError::UnsupportedTransferEncoding(_)) => {
+                (Status::NotImplemented, err.to_string())
+            }
+            Err(err) => (Status::BadRequest, err.to_string()),
+        };
+
+        // After a bad request we can't tell where the next one starts
+        let mut response = Response::text(status, message).with_header("Connection", "close");
+        let stream = &mut self.reader.get_mut().stream;
+        let written = response.write_to(stream);
+        self.log(None, &response, *written.as_ref().unwrap_or(&0), options);
+        written?;
+        let stream = &mut self.reader.get_mut().stream;
+        stream.close_notify()?;
+        linger(stream.tcp())?;
+        Ok(None)
+    }
+
+    // Reads a request that has started to arrive
+    //
+    // Each part has to arrive before its deadline, however the client spreads
+    // it out. A timeout for every read alone would let a client that sends a
+    // byte now and then keep the worker forever.
+    fn read_parts(&mut self, options: &ConnectionOptions) -> Result<Request, ParseError> {
+        self.reader.get_mut().deadline = Instant::now() + options.header_timeout;
+        let mut request = Request::read_head(&mut self.reader, &options.limits)?;
+
+        // A client that asks first waits for a go-ahead before sending the body
+        if request.version == Version::Http11
+            && request.headers.has_token("Expect", "100-continue")
+            && wants_body(&request, &options.limits)
+        {
+            let stream = &mut self.reader.get_mut().stream;
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            stream.flush()?;
+        }
+
+        self.reader.get_mut().deadline = Instant::now() + options.body_timeout;
+        request.read_body(&mut self.reader, &options.limits)?;
+        Ok(request)
+    }
+
+    /// Answers `request`, returns whether the connection stays open for another one
+    ///
+    /// The middlewares may change `request` on the way, the access log records it as they left it.
+    pub fn respond(
+        &mut self,
+        request: &mut Request,
+        router: &Router,
+        options: &ConnectionOptions,
+    ) -> io::Result<bool> {
+        self.served += 1;
+        // The router runs the middlewares and picks the handler (or answers 404/405 by itself)
+        let mut response = router.handle(request);
+
+        // Without chunks, the end of an HTTP/1.0 body of unknown length is the end of the connection
+        let close_delimited = request.version == Version::Http10
+            && request.method != Method::Head
+            && response.status.allows_body()
+            && response.body.is_chunked();
+        let keep_alive = wants_keep_alive(request)
+            && !close_delimited
+            && self.served < options.max_requests
+            && !options.shutdown.is_triggered();
+
+        if !keep_alive {
+            response.headers.insert("Connection", "close");
+        } else if request.version == Version::Http10 {
+            // HTTP/1.0 clients only keep the connection if we say so
+            response.headers.insert("Connection", "keep-alive");
+        }
+
+        // The reader owns the stream, writing doesn't touch what it buffered
+        let writer = &mut self.reader.get_mut().stream;
+
+        // HEAD gets the same headers as GET, but no body
+        let written = if request.method == Method::Head {
+            response.write_head_to(writer).map(|()| 0)
+        } else if request.version == Version::Http10 {
+            response.write_to_http10(writer)
+        } else {
+            response.write_to(writer)
+        };
+        self.log(
+            Some(request),
+            &response,
+            *written.as_ref().unwrap_or(&0),
+            options,
+        );
+        written?;
+
+        if !keep_alive {
+            self.reader.get_mut().stream.close_notify()?;
+        }
+        Ok(keep_alive)
+    }
+
+    // `bytes` is how much of the body went out, a chunked one has no length before
+    fn log(
+        &self,
+        request: Option<&Request>,
+        response: &Response,
+        bytes: u64,
+        options: &ConnectionOptions,
+    ) {
+        let Some(access_log) = &options.access_log else {
+            return;
+        };
+        access_log.record(&AccessRecord {
+            client: self.client,
+            time: self.arrived,
+            request,
+            status: response.status,
+            bytes,
+            duration: self.started.elapsed(),
+        });
+    }
+}
+
+// The stream of a Connection, reads fail once the deadline has passed
+struct TimedStream {
+    stream: Stream,
+    deadline: Instant,
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        // The socket gives up on its own when the time is up
+        self.stream.tcp().set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+// A plain or an encrypted connection, HTTP goes over both the same way
+enum Stream {
+    Plain(TcpStream),
+    // Boxed, the TLS state is big
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    // The socket underneath, for the timeouts and the address
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    // Tells a TLS client that the connection ends here and not because someone cut it
+    fn close_notify(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => Ok(()),
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// HTTP/1.1 keeps the connection unless told otherwise, HTTP/1.0 is the other way around
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+// Whether there is a body on its way that we are willing to read
+fn wants_body(request: &Request, limits: &Limits) -> bool {
+    match content_length(&request.headers) {
+        // Too big or malformed, read_body refuses it without waiting for it
+        Ok(Some(length)) => length > 0 && length <= limits.max_body_size,
+        Ok(None) => request.headers.contains("Transfer-Encoding"),
+        Err(_) => false,
+    }
+}
+
+// Timeouts show up as WouldBlock on Unix and as TimedOut on Windows
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
@@ -0,0 +1,208 @@
+// HTTP dates look like "Sun, 06 Nov 1994 08:49:37 GMT" (RFC 9110 calls it IMF-fixdate)
+// The standard library has no calendar support, so the conversion from
+// seconds since the UNIX epoch to a date is done by hand here
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats `time` as an HTTP date, for the `Date` and `Last-Modified` headers
+pub fn format_http_date(time: SystemTime) -> String {
+    let date = DateTime::from(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday, that's why DAYS starts with "Thu"
+        DAYS[(date.days % 7) as usize],
+        date.day,
+        MONTHS[(date.month - 1) as usize],
+        date.year,
+        date.hour,
+        date.minute,
+        date.second
+    )
+}
+
+/// Reads an HTTP date like the ones `format_http_date` writes
+///
+/// Only IMF-fixdate is understood, not the obsolete RFC 850 and asctime
+/// formats. A date we can't read is the same as no date for conditional
+/// requests, so the worst case is sending the whole body.
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    // "Sun, 06 Nov 1994 08:49:37 GMT"
+    let (_, rest) = text.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let (day, month, year, time, zone) = (
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+    );
+    if zone != "GMT" || parts.next().is_some() || day.len() != 2 || year.len() != 4 {
+        return None;
+    }
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|part| match part.len() {
+        2 => part.parse::<u64>().ok(),
+        _ => None,
+    });
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some()
+        || year < 1970
+        || day == 0
+        || day > 31
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Formats `time` the way the Common Log Format does, like "10/Oct/2000:13:55:36 +0000"
+pub fn format_log_date(time: SystemTime) -> String {
+    let date = DateTime::from(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        date.day,
+        MONTHS[(date.month - 1) as usize],
+        date.year,
+        date.hour,
+        date.minute,
+        date.second
+    )
+}
+
+/// Formats `time` as RFC 3339 in UTC with milliseconds, like "2000-10-10T13:55:36.000Z"
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let date = DateTime::from(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        date.year, date.month, date.day, date.hour, date.minute, date.second, date.millis
+    )
+}
+
+// A point in time split into the fields the formats need, always in UTC
+struct DateTime {
+    // Days since 1970-01-01, for the day of the week
+    days: u64,
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    millis: u32,
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> DateTime {
+        // Dates before 1970 never show up here, so they are clamped to the epoch
+        let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = elapsed.as_secs();
+
+        let days = secs / 86_400;
+        let seconds_of_day = secs % 86_400;
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            days,
+            year,
+            month,
+            day,
+            hour: seconds_of_day / 3600,
+            minute: seconds_of_day % 3600 / 60,
+            second: seconds_of_day % 60,
+            millis: elapsed.subsec_millis(),
+        }
+    }
+}
+
+// Converts days since 1970-01-01 into (year, month, day)
+// This is Howard Hinnant's "civil_from_days" algorithm, restricted to dates after the epoch
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shifting the epoch to 0000-03-01 puts the leap day at the end of the year
+    let z = days + 719_468;
+    // An era is a 400 year cycle, which always has 146097 days
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March, so February is the last (shortest) one
+    let shifted_month = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// The other way around, from (year, month, day) to days since 1970-01-01
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // The same shift to years that start in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn formats_imf_fixdate() {
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT", format_http_date(at(0)));
+        // The example from RFC 9110
+        assert_eq!(
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            format_http_date(at(784_111_777))
+        );
+        // Leap day
+        assert_eq!(
+            "Thu, 29 Feb 2024 12:00:00 GMT",
+            format_http_date(at(1_709_208_000))
+        );
+    }
+
+    #[test]
+    fn formats_log_dates() {
+        let time = at(971_186_136) + Duration::from_millis(42);
+        assert_eq!("10/Oct/2000:13:55:36 +0000", format_log_date(time));
+        assert_eq!("2000-10-10T13:55:36.042Z", format_rfc3339(time));
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        for secs in [0, 784_111_777, 951_782_400, 1_709_208_000, 4_102_444_799] {
+            assert_eq!(Some(at(secs)), parse_http_date(&format_http_date(at(secs))));
+        }
+        assert_eq!(None, parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("Sun Nov  6 08:49:37 1994"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 8:49:37 GMT"));
+        assert_eq!(None, parse_http_date("yesterday"));
+    }
+}
@@ -0,0 +1,144 @@
+// Header fields of a request or a response
+// HTTP header names are case-insensitive ("Content-Length" == "content-length"),
+// so every lookup here compares names with eq_ignore_ascii_case
+
+/// An ordered list of HTTP header fields.
+///
+/// The same name may appear more than once (for example `Set-Cookie`),
+/// so the fields are kept in a Vec instead of a HashMap.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { fields: Vec::new() }
+    }
+
+    /// Returns the value of the first field called `name`, if any
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of the fields called `name`, in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name` to `value`, replacing any fields with the same name
+    ///
+    /// # Panics
+    ///
+    /// If `name` or `value` contains a CR or LF, which would let it add
+    /// header fields of its own (or a whole response) when written out.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Adds a field without touching the existing ones with the same name
+    ///
+    /// # Panics
+    ///
+    /// Like `insert`, if `name` or `value` contains a CR or LF.
+    pub fn append(&mut self, name: &str, value: &str) {
+        assert!(
+            !has_line_break(name) && !has_line_break(value),
+            "line break in the header field {:?}: {:?}",
+            name,
+            value
+        );
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    /// Checks if a comma separated header (like `Connection: keep-alive, Upgrade`)
+    /// lists `token`, ignoring case
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+pub(crate) fn has_line_break(text: &str) -> bool {
+    text.contains(['\r', '\n'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ignores_case() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+
+        assert_eq!(Some("text/html"), headers.get("content-type"));
+        assert!(headers.contains("CONTENT-TYPE"));
+        assert_eq!(None, headers.get("Content-Length"));
+    }
+
+    #[test]
+    fn insert_replaces_and_append_keeps() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(
+            vec!["a=1", "b=2"],
+            headers.get_all("Set-Cookie").collect::<Vec<_>>()
+        );
+
+        headers.insert("SET-COOKIE", "c=3");
+        assert_eq!(
+            vec!["c=3"],
+            headers.get_all("Set-Cookie").collect::<Vec<_>>()
+        );
+        assert_eq!(1, headers.len());
+    }
+
+    #[test]
+    fn tokens_in_comma_separated_values() {
+        let mut headers = Headers::new();
+        headers.append("Connection", "keep-alive, Upgrade");
+
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+    }
+
+    #[test]
+    #[should_panic(expected = "line break")]
+    fn line_breaks_are_refused() {
+        let mut headers = Headers::new();
+        headers.insert("Location", "/a\r\nSet-Cookie: session=stolen");
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+// The place where the worker leaves the result and the handle picks it up
+struct Slot<T> {
+    result: Mutex<Option<thread::Result<T>>>,
+    // Condvar lets join sleep until the result arrives instead of spinning
+    done: Condvar,
+}
+
+impl<T> Slot<T> {
+    fn result(&self) -> MutexGuard<'_, Option<thread::Result<T>>> {
+        self.result.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A handle to the result of a job started with `ThreadPool::spawn`
+///
+/// It works like `thread::JoinHandle`: `join` waits for the job and returns
+/// what the closure returned, or the panic payload if it panicked.
+pub struct JobHandle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish and returns its result
+    pub fn join(self) -> thread::Result<T> {
+        let mut result = self.slot.result();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            result = self
+                .slot
+                .done
+                .wait(result)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Like `join`, but gives up after `timeout` and hands the handle back
+    pub fn join_timeout(self, timeout: Duration) -> Result<thread::Result<T>, JobHandle<T>> {
+        let result = self.slot.result();
+        let (mut result, _) = self
+            .slot
+            .done
+            .wait_timeout_while(result, timeout, |result| result.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+
+        match result.take() {
+            Some(result) => Ok(result),
+            None => {
+                drop(result);
+                Err(self)
+            }
+        }
+    }
+
+    /// Returns the result if the job is done, or the handle back if it is not
+    ///
+    /// Taking `self` means a result can't be taken twice
+    pub fn try_join(self) -> Result<thread::Result<T>, JobHandle<T>> {
+        let result = self.slot.result().take();
+        match result {
+            Some(result) => Ok(result),
+            None => Err(self),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.slot.result().is_some()
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+/// The worker's side of a `JobHandle`
+///
+/// If it is dropped without a result, because the job was thrown away before
+/// it could run, the handle gets an error so `join` never waits forever.
+pub(crate) struct Completer<T> {
+    slot: Option<Arc<Slot<T>>>,
+}
+
+impl<T> Completer<T> {
+    pub(crate) fn complete(mut self, result: thread::Result<T>) {
+        if let Some(slot) = self.slot.take() {
+            *slot.result() = Some(result);
+            slot.done.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            *slot.result() = Some(Err(Box::new("job was dropped before it ran")));
+            slot.done.notify_all();
+        }
+    }
+}
+
+/// Creates the two connected ends for one job
+pub(crate) fn handle<T>() -> (Completer<T>, JobHandle<T>) {
+    let slot = Arc::new(Slot {
+        result: Mutex::new(None),
+        done: Condvar::new(),
+    });
+
+    (
+        Completer {
+            slot: Some(Arc::clone(&slot)),
+        },
+        JobHandle { slot },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_waits_for_the_result() {
+        let (completer, handle) = handle();
+
+        let worker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            completer.complete(Ok(42));
+        });
+
+        assert_eq!(42, handle.join().unwrap());
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn try_join_gives_the_handle_back() {
+        let (completer, handle) = handle::<&str>();
+
+        let handle = handle.try_join().unwrap_err();
+        assert!(!handle.is_finished());
+        let handle = handle.join_timeout(Duration::from_millis(10)).unwrap_err();
+
+        completer.complete(Ok("done"));
+        assert!(handle.is_finished());
+        assert_eq!("done", handle.try_join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn dropped_job_is_an_error() {
+        let (completer, handle) = handle::<()>();
+        drop(completer);
+
+        let payload = handle.join().unwrap_err();
+        assert_eq!(
+            Some(&"job was dropped before it ran"),
+            payload.downcast_ref::<&str>()
+        );
+    }
+}
@@ -1,13 +1,418 @@
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use queue::{Job, JobQueue, Outcome, Pop};
+use stats::Metrics;
+use timer::Timer;
+
+// The logging macros are exported at the top of the crate
+
+pub mod access_log;
+pub mod cache_control;
+pub mod compression;
+pub mod config;
+pub mod connection;
+pub mod date;
+pub mod headers;
+pub mod job;
+pub mod log;
+pub mod middleware;
+pub mod mime;
+pub mod queue;
+pub mod range;
+pub mod redirect;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod scope;
+pub mod shutdown;
+pub mod static_files;
+pub mod stats;
+pub mod timer;
+pub mod tls;
+
+pub use access_log::{AccessLog, LogFormat};
+pub use cache_control::CacheControl;
+pub use compression::Compression;
+pub use config::{Config, ConfigError, LogLevel};
+pub use headers::Headers;
+pub use job::JobHandle;
+pub use middleware::{Middleware, Next};
+pub use queue::{OverflowPolicy, Priority, QueueFullError};
+pub use redirect::HttpsRedirect;
+pub use request::{Limits, Method, ParseError, Request, Version};
+pub use response::{Body, BodyWriter, Response, Status};
+pub use router::{Params, Router};
+pub use scope::Scope;
+pub use shutdown::Shutdown;
+pub use static_files::StaticFiles;
+pub use stats::{Histogram, PoolStats};
+pub use timer::TimerHandle;
+pub use tls::{Certificate, Tls, TlsBuilder, TlsError};
 
 // Public API for the ThreadPool
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    // sender is used to send the jobs to the workers
-    sender: mpsc::Sender<Message>,
+    // State shared with the worker threads
+    shared: Arc<Shared>,
+}
+
+/// Why a ThreadPool could not be built
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one thread
+    ZeroSize,
+    /// A bounded queue needs room for at least one job
+    ZeroCapacity,
+    /// `min_workers` is larger than `max_workers`
+    InvalidRange { min: usize, max: usize },
+    /// The operating system refused to create a worker thread
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "a thread pool needs at least one thread"),
+            PoolCreationError::ZeroCapacity => {
+                write!(f, "a bounded job queue needs room for at least one job")
+            }
+            PoolCreationError::InvalidRange { min, max } => write!(
+                f,
+                "the pool can't keep {} workers when it may have at most {}",
+                min, max
+            ),
+            PoolCreationError::Spawn(err) => write!(f, "failed to spawn a worker thread: {}", err),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize
+            | PoolCreationError::ZeroCapacity
+            | PoolCreationError::InvalidRange { .. } => None,
+            PoolCreationError::Spawn(err) => Some(err),
+        }
+    }
+}
+
+/// Configures a ThreadPool before it starts, the same way `thread::Builder` configures a thread
+///
+/// ```
+/// use server::{OverflowPolicy, ThreadPool};
+///
+/// let pool = ThreadPool::builder()
+///     .size(4)
+///     .queue_capacity(64)
+///     .overflow_policy(OverflowPolicy::Reject)
+///     .build()
+///     .unwrap();
+/// assert!(pool.try_execute(|| println!("hi")).is_ok());
+/// ```
+#[derive(Debug, Clone)]
+pub struct PoolBuilder {
+    min_workers: usize,
+    max_workers: usize,
+    keep_alive: Duration,
+    queue_capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    starvation_timeout: Duration,
+}
+
+impl Default for PoolBuilder {
+    fn default() -> PoolBuilder {
+        PoolBuilder::new()
+    }
+}
+
+impl PoolBuilder {
+    /// One worker per CPU, and a queue without a limit
+    pub fn new() -> PoolBuilder {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        PoolBuilder {
+            min_workers: cpus,
+            max_workers: cpus,
+            keep_alive: Duration::from_secs(60),
+            queue_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+            starvation_timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// A fixed number of threads, the same as setting `min_workers` and `max_workers`
+    pub fn size(mut self, size: usize) -> PoolBuilder {
+        self.min_workers = size;
+        self.max_workers = size;
+        self
+    }
+
+    /// The workers that are started right away and never retire, it may be 0
+    pub fn min_workers(mut self, min: usize) -> PoolBuilder {
+        self.min_workers = min;
+        self
+    }
+
+    /// The limit for extra workers, which are started when jobs wait in the queue
+    pub fn max_workers(mut self, max: usize) -> PoolBuilder {
+        self.max_workers = max;
+        self
+    }
+
+    /// How long an extra worker may sit idle before it retires, 60 seconds by default
+    pub fn keep_alive(mut self, keep_alive: Duration) -> PoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// How many jobs may wait for a worker before the overflow policy kicks in
+    pub fn queue_capacity(mut self, capacity: usize) -> PoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What to do with a new job when the queue is full, `Block` by default
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> PoolBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// How long jobs may wait while higher priority jobs keep going first, 1 second by default
+    ///
+    /// After that their priority gets the next free worker.
+    pub fn starvation_timeout(mut self, timeout: Duration) -> PoolBuilder {
+        self.starvation_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.max_workers == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.min_workers > self.max_workers {
+            return Err(PoolCreationError::InvalidRange {
+                min: self.min_workers,
+                max: self.max_workers,
+            });
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+
+        // Arc is a thread safe reference counting pointer
+        let shared = Arc::new(Shared {
+            queue: JobQueue::new(
+                self.queue_capacity,
+                self.overflow_policy,
+                self.starvation_timeout,
+            ),
+            workers: Mutex::new(Vec::with_capacity(self.max_workers)),
+            panic_handler: RwLock::new(None),
+            next_id: AtomicUsize::new(0),
+            metrics: Metrics::default(),
+            timer: Timer::default(),
+            min_workers: self.min_workers,
+            max_workers: self.max_workers,
+            keep_alive: self.keep_alive,
+        });
+
+        // The pool is created empty and filled one worker at a time, so if a spawn
+        // fails, dropping the pool shuts down the workers that already started
+        let pool = ThreadPool { shared };
+
+        for _ in 0..self.min_workers {
+            // Creating the workers
+            let worker = Worker::build(&pool.shared).map_err(PoolCreationError::Spawn)?;
+            pool.shared.workers().push(worker);
+        }
+
+        Ok(pool)
+    }
+}
+
+/// Reads the stats of a ThreadPool, returned by `ThreadPool::monitor`
+///
+/// It can be cloned and outlive the pool, after that the stats stop changing.
+#[derive(Clone)]
+pub struct PoolMonitor {
+    shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        let workers = self.shared.workers().len();
+        self.shared
+            .metrics
+            .snapshot(self.shared.queue.len(), workers)
+    }
+}
+
+/// Queues jobs on a ThreadPool from places that can't own the pool, returned by `ThreadPool::handle`
+///
+/// The main use is a job that hands work on to another job. A job can't hold the
+/// pool itself, the last owner would then drop it on a worker, where it would wait
+/// for itself to finish. Once the pool is stopped, the handle's jobs are refused.
+#[derive(Clone)]
+pub struct PoolHandle {
+    shared: Arc<Shared>,
+}
+
+impl PoolHandle {
+    /// Same as `ThreadPool::execute`
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, f);
+    }
+
+    /// Same as `ThreadPool::execute_with_priority`
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(err) = self.try_execute_with_priority(priority, f) {
+            warn!("Dropped a job: {}", err);
+        }
+    }
+
+    /// Same as `ThreadPool::try_execute_with_priority`
+    pub fn try_execute_with_priority<F>(
+        &self,
+        priority: Priority,
+        f: F,
+    ) -> Result<(), QueueFullError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.submit(Job::new(f).with_priority(priority))
+    }
+}
+
+/// Details about a job that panicked, given to the panic handler
+#[derive(Debug, Clone)]
+pub struct JobPanic {
+    /// The worker that was running the job
+    pub worker_id: usize,
+    /// The message passed to `panic!`, if it was a string
+    pub message: String,
+}
+
+type PanicHandler = Box<dyn Fn(&JobPanic) + Send + Sync>;
+
+// Everything the workers need to reach, behind one Arc
+struct Shared {
+    // The jobs waiting for a worker
+    queue: JobQueue,
+    // The workers are stored here and not in ThreadPool, so a dying worker can replace itself
+    workers: Mutex<Vec<Worker>>,
+    panic_handler: RwLock<Option<PanicHandler>>,
+    // Replacements get new ids, so log lines never mix up two threads
+    next_id: AtomicUsize,
+    metrics: Metrics,
+    // Holds the jobs of execute_after and execute_every until they are due
+    timer: Timer,
+    min_workers: usize,
+    max_workers: usize,
+    keep_alive: Duration,
+}
+
+impl Shared {
+    // A poisoned lock only means a thread panicked while holding it,
+    // the data inside is still fine for us, so we keep going
+    fn workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn submit(self: &Arc<Self>, job: Job) -> Result<(), QueueFullError> {
+        // We need to get a worker from the pool
+        // and send the job (closure) to the worker
+        // A refused job is dropped here, which fails its JobHandle if it has one
+        self.queue.push(job).map_err(|_| QueueFullError)?;
+        self.grow();
+        Ok(())
+    }
+
+    // Starts another worker if jobs are waiting and nobody is free to take them
+    fn grow(self: &Arc<Self>) {
+        // This runs for every job, so the cheap checks come before the lock
+        if self.min_workers == self.max_workers || !self.queue.is_backed_up() {
+            return;
+        }
+
+        let mut workers = self.workers();
+        if workers.len() >= self.max_workers || !self.queue.is_backed_up() {
+            return;
+        }
+        match Worker::build(self) {
+            Ok(worker) => workers.push(worker),
+            // The job still runs once a busy worker is free
+            Err(err) => error!("Failed to start an extra worker: {}", err),
+        }
+    }
+
+    // Called by a worker that was idle for keep_alive, true if it should stop
+    //
+    // The queue is checked while holding the workers lock, which grow takes too,
+    // so a job pushed right now is either seen here or makes grow start a new worker
+    fn retire(&self, id: usize) -> bool {
+        let mut workers = self.workers();
+        if workers.len() <= self.min_workers || !self.queue.is_empty() {
+            return false;
+        }
+        // Dropping the JoinHandle detaches the thread, which is about to return anyway
+        workers.retain(|worker| worker.id != id);
+        true
+    }
+
+    // Runs a job on the worker with this id, a panic in it is reported and stops there
+    fn run(&self, worker_id: usize, job: Job) {
+        self.metrics.job_started(job.queued_at.elapsed());
+        let start = Instant::now();
+
+        // catch_unwind stops a panicking job from taking the thread down with it
+        // AssertUnwindSafe is fine because the job is gone after this call,
+        // nobody can observe what it left half-done
+        let result = panic::catch_unwind(AssertUnwindSafe(job.task));
+
+        let panicked = !matches!(result, Ok(Outcome::Completed));
+        self.metrics.job_finished(start.elapsed(), panicked);
+        if let Err(payload) = result {
+            self.report_panic(worker_id, payload.as_ref());
+        }
+    }
+
+    fn report_panic(&self, worker_id: usize, payload: &(dyn Any + Send)) {
+        // panic!("...") gives a &str, panic!("{}", x) gives a String
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<dyn Any>".to_string()
+        };
+        let job_panic = JobPanic { worker_id, message };
+
+        let handler = self
+            .panic_handler
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        match handler.as_ref() {
+            Some(handler) => handler(&job_panic),
+            None => error!(
+                "Worker {} caught a panic in a job: {}",
+                worker_id, job_panic.message
+            ),
+        }
+    }
 }
 
 // Worker struct will store the id and the thread
@@ -17,63 +422,92 @@ pub struct Worker {
     thread: Option<thread::JoinHandle<()>>,
 }
 
-// Job type alias
-// This is a type alias for a trait object that holds the type of closure that execute will execute
-type Job = Box<dyn FnOnce() + Send + 'static>;
+impl Worker {
+    fn build(shared: &Arc<Shared>) -> io::Result<Worker> {
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
 
-// This is the message that will be sent to the workers
-// Either it will be a new job or a terminate message
-enum Message {
-    NewJob(Job),
-    Terminate,
-}
+        // thread::Builder::spawn returns an error instead of panicking when
+        // the OS can't create the thread, and lets us name it for debuggers
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
 
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        // We need to move the receiver to the thread to avoid the receiver being dropped
-        // Loop will keep the thread alive and looking for jobs
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-
-            // Match the message to see if it is a new job or a terminate message
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
-                    job();
-                }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
-                    break;
-                }
-            }
-        });
-        Worker {
+        // We need to move the shared state to the thread to avoid it being dropped
+        let shared = Arc::clone(shared);
+        let thread = builder.spawn(move || {
+            // Every worker has its own deque in the queue
+            shared.queue.register(id);
+            // When this thread stops, the sentinel hands its jobs back,
+            // and if it died, starts a replacement
+            let _sentinel = Sentinel {
+                id,
+                shared: &shared,
+            };
+            Worker::run(id, &shared);
+        })?;
+
+        Ok(Worker {
             id,
             thread: Some(thread),
+        })
+    }
+
+    // Loop will keep the thread alive and looking for jobs
+    fn run(id: usize, shared: &Shared) {
+        loop {
+            let job = match shared.queue.pop(shared.keep_alive) {
+                Pop::Job(job) => job,
+                // Extra workers go away when there is nothing to do
+                Pop::TimedOut if shared.retire(id) => {
+                    debug!("Worker {} was idle, retiring.", id);
+                    return;
+                }
+                Pop::TimedOut => continue,
+                // The pool is shutting down and the queue is empty
+                Pop::Closed => break,
+            };
+
+            shared.run(id, job);
         }
+        debug!("Worker {} was told to terminate.", id);
     }
 }
 
-impl Drop for ThreadPool {
+// Lives on the worker's stack, its drop runs when the worker stops, and also while
+// the thread unwinds after a panic that escaped the job, for example one thrown by
+// the panic handler itself
+struct Sentinel<'a> {
+    id: usize,
+    shared: &'a Arc<Shared>,
+}
+
+impl Drop for Sentinel<'_> {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
-        // Looping through the workers
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
-        }
-
-        println!("Shutting down all workers.");
-        for worker in &mut self.workers {
-            println!("Terminating worker {}", worker.id);
-            // If Option is Some, we want to take the value out of the Some variant
-            if let Some(thread) = worker.thread.take() {
-                // We want to wait for the thread to finish
-                thread.join().unwrap();
-            }
+        // Jobs left in this worker's deque go back to the injector
+        self.shared.queue.unregister();
+
+        if !thread::panicking() {
+            return;
+        }
+
+        error!("Worker {} died, starting a replacement.", self.id);
+        let replacement = Worker::build(self.shared);
+
+        let mut workers = self.shared.workers();
+        // The dead thread is not joined, it is already on its way out
+        workers.retain(|worker| worker.id != self.id);
+        match replacement {
+            Ok(worker) => workers.push(worker),
+            Err(err) => error!("Failed to replace worker {}: {}", self.id, err),
         }
     }
 }
 
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Without a deadline we wait for every worker, however long it takes
+        self.stop(None);
+    }
+}
+
 impl ThreadPool {
     /// Create a new ThreadPool
     ///
@@ -81,31 +515,43 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is 0 or less
+    /// The `new` function will panic if the size is 0 or if a thread can't be spawned,
+    /// use `build` to handle those cases
     pub fn new(size: usize) -> ThreadPool {
-        // Program will panic if the size is 0 or less
-        assert!(size > 0);
-
-        // Creating a new channel
-        let (sender, receiver) = mpsc::channel();
-
-        let mut workers = Vec::with_capacity(size as usize);
-
-        let receiver = Arc::new(Mutex::new(receiver));
+        ThreadPool::build(size).unwrap_or_else(|err| panic!("{}", err))
+    }
 
-        for id in 0..size {
-            // Creating the workers
-            // We want the workers to share the receiver, so we need to use Arc smart pointer
-            // Arc is a thread safe reference counting pointer
+    /// Create a new ThreadPool, returning an error instead of panicking
+    ///
+    /// The size is the number of threads in the pool
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder().size(size).build()
+    }
 
-            // For thread mutability we can use Mutex smart pointer
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
+    /// Starts a `PoolBuilder`, for pools that need more than a size
+    pub fn builder() -> PoolBuilder {
+        PoolBuilder::new()
+    }
 
-        ThreadPool { workers, sender }
+    /// Sets the function that is called when a job panics
+    ///
+    /// The worker survives the panic and goes on with the next job.
+    /// Without a handler the panic is printed to stderr.
+    pub fn set_panic_handler<F>(&self, handler: F)
+    where
+        F: Fn(&JobPanic) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .panic_handler
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(handler));
     }
 
     // We want execute to work simmilar to the thread::spawn function
+    //
+    // With the Reject policy a job that doesn't fit is dropped with a message on stderr,
+    // use try_execute to find out about it
     pub fn execute<F>(&self, f: F)
     where
         // FnOnce means that the closure takes ownership of the variables
@@ -113,10 +559,651 @@ impl ThreadPool {
         // 'static means that the lifetime of the closure is the entire program
         F: FnOnce() + Send + 'static,
     {
-        // We need to get a worker from the pool
-        // and send the job (closure) to the worker
-        let job = Box::new(f);
-        // Send the job to the channel
-        self.sender.send(Message::NewJob(job)).unwrap();
+        self.execute_with_priority(Priority::Normal, f);
+    }
+
+    /// Like `execute`, but the job waits in the lane of `priority`
+    ///
+    /// Workers take high priority jobs before normal ones and normal before low,
+    /// unless a lane has waited longer than the starvation timeout.
+    ///
+    /// ```
+    /// use server::{Priority, ThreadPool};
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// pool.execute_with_priority(Priority::Low, || println!("when there is time"));
+    /// pool.execute_with_priority(Priority::High, || println!("right away"));
+    /// ```
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(err) = self.try_execute_with_priority(priority, f) {
+            warn!("Dropped a job: {}", err);
+        }
+    }
+
+    /// Like `execute`, but tells the caller when the job was not queued
+    ///
+    /// That only happens with `OverflowPolicy::Reject` when the queue is full.
+    /// With `Block` this waits for room, with `DropOldest` the oldest waiting
+    /// job of the lowest priority is dropped instead and this one is queued.
+    ///
+    /// Jobs started by a job running on this pool are always queued.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), QueueFullError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute_with_priority(Priority::Normal, f)
+    }
+
+    /// `try_execute` with a priority, see `execute_with_priority`
+    pub fn try_execute_with_priority<F>(
+        &self,
+        priority: Priority,
+        f: F,
+    ) -> Result<(), QueueFullError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.submit(Job::new(f).with_priority(priority))
+    }
+
+    /// The number of jobs waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// The number of worker threads right now, between `min_workers` and `max_workers`
+    pub fn worker_count(&self) -> usize {
+        self.shared.workers().len()
+    }
+
+    /// What the pool is doing right now and has done so far
+    ///
+    /// ```
+    /// let pool = server::ThreadPool::new(2);
+    /// let monitor = pool.monitor();
+    /// pool.execute(|| println!("hi"));
+    /// pool.execute(|| panic!("oops"));
+    ///
+    /// // Dropping the pool waits for both jobs
+    /// drop(pool);
+    /// let stats = monitor.stats();
+    /// assert_eq!((1, 1), (stats.completed, stats.panicked));
+    /// assert_eq!(2, stats.run_time.count);
+    /// ```
+    pub fn stats(&self) -> PoolStats {
+        self.monitor().stats()
+    }
+
+    /// A handle for reading the stats from somewhere the pool itself can't go,
+    /// like a request handler running on one of the workers
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// A handle for queueing jobs from inside other jobs
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Runs jobs that borrow from the caller, and waits for all of them
+    ///
+    /// This is the pool version of `thread::scope`. Jobs started with `s.spawn`
+    /// may borrow anything that outlives the call, because `scope` only returns
+    /// once every one of them is done. If `f` or a job panics, the panic is
+    /// passed on after that.
+    ///
+    /// ```
+    /// let pool = server::ThreadPool::new(4);
+    /// let mut numbers = vec![1, 2, 3, 4, 5, 6, 7, 8];
+    ///
+    /// pool.scope(|s| {
+    ///     for chunk in numbers.chunks_mut(2) {
+    ///         s.spawn(move || chunk.iter_mut().for_each(|n| *n *= 10));
+    ///     }
+    /// });
+    /// assert_eq!(vec![10, 20, 30, 40, 50, 60, 70, 80], numbers);
+    /// ```
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        scope::run(&self.shared, f)
+    }
+
+    /// Queues `f` once `delay` has passed
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let pool = server::ThreadPool::new(2);
+    /// let reminder = pool.execute_after(Duration::from_secs(30), || println!("30 seconds later"));
+    ///
+    /// // Changed our mind
+    /// assert!(reminder.cancel());
+    /// ```
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        timer::execute_after(&self.shared, delay, f)
+    }
+
+    /// Queues `f` every `interval`, starting one interval from now, until it is cancelled
+    ///
+    /// A run is skipped while the one before is still queued or running, so a
+    /// slow job never runs twice at the same time. Stopping the pool stops it too.
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        timer::execute_every(&self.shared, interval, f)
+    }
+
+    /// Runs `f` on the pool and returns a handle to its result
+    ///
+    /// This is the pool version of `thread::spawn`. If `f` panics, the worker
+    /// survives and `join` on the handle returns the panic payload (the panic
+    /// handler is not called, the handle owner decides what to do with it).
+    ///
+    /// ```
+    /// let pool = server::ThreadPool::new(2);
+    /// let handle = pool.spawn(|| (1..=10).sum::<u32>());
+    /// assert_eq!(55, handle.join().unwrap());
+    /// ```
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (completer, handle) = job::handle();
+        let job = Job::with_outcome(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let outcome = match result {
+                Ok(_) => Outcome::Completed,
+                Err(_) => Outcome::Panicked,
+            };
+            completer.complete(result);
+            outcome
+        });
+
+        // A job that was not queued drops its completer, which fails the handle
+        if let Err(err) = self.shared.submit(job) {
+            warn!("Dropped a job: {}", err);
+        }
+        handle
+    }
+
+    /// Stops the pool, waiting at most `timeout` for the workers to finish
+    ///
+    /// Jobs that are already queued still run before the workers stop.
+    /// Returns `false` if some workers were still busy when the deadline passed,
+    /// those threads are left running on their own and die with the process.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> bool {
+        self.stop(Some(Instant::now() + timeout))
+    }
+
+    fn stop(&mut self, deadline: Option<Instant>) -> bool {
+        // Scheduled jobs that are not due yet are dropped, the timer thread
+        // goes away with the pool
+        self.shared.timer.close();
+
+        debug!("Sending terminate message to all workers.");
+        // Closing the queue tells every worker, even ones started later, to stop once it is empty
+        // It also turns away jobs from PoolHandles, even when no worker is running
+        // right now (min_workers 0, or all of them retired), so none gets started.
+        // Both steps may run twice, once from shutdown_timeout and once from drop.
+        self.shared.queue.close();
+        // The timer may be waiting for room in the queue, closing the queue woke it up
+        self.shared.timer.join();
+
+        debug!("Shutting down all workers.");
+        let mut all_finished = true;
+        loop {
+            // Taking the workers out of the lock, so a dying worker can still put its replacement in
+            // Replacements for workers that died while we were waiting
+            // see the closed queue and stop on their own
+            let workers: Vec<Worker> = self.shared.workers().drain(..).collect();
+            if workers.is_empty() {
+                break;
+            }
+            for mut worker in workers {
+                debug!("Terminating worker {}", worker.id);
+                // If Option is Some, we want to take the value out of the Some variant
+                if let Some(thread) = worker.thread.take() {
+                    if let Some(deadline) = deadline {
+                        // JoinHandle has no join with a timeout, so we poll is_finished
+                        while !thread.is_finished() && Instant::now() < deadline {
+                            thread::sleep(Duration::from_millis(10));
+                        }
+                        if !thread.is_finished() {
+                            warn!("Worker {} is still busy, leaving it behind.", worker.id);
+                            all_finished = false;
+                            continue;
+                        }
+                    }
+                    // We want to wait for the thread to finish
+                    // An error means the thread panicked, its replacement is picked up below
+                    if thread.join().is_err() {
+                        warn!("Worker {} had panicked.", worker.id);
+                    }
+                }
+            }
+        }
+
+        all_finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+
+    // Keeps the only worker busy until the returned sender is dropped
+    fn occupy(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = wait.recv();
+        });
+        running.recv().unwrap();
+        release
+    }
+
+    #[test]
+    fn build_rejects_zero_size() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one thread")]
+    fn new_panics_on_zero_size() {
+        ThreadPool::new(0);
+    }
+
+    #[test]
+    fn builder_rejects_zero_capacity() {
+        assert!(matches!(
+            ThreadPool::builder().size(1).queue_capacity(0).build(),
+            Err(PoolCreationError::ZeroCapacity)
+        ));
+    }
+
+    #[test]
+    fn reject_policy_refuses_jobs_when_saturated() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+
+        assert!(pool.try_execute(|| {}).is_ok());
+        assert_eq!(1, pool.queued());
+        assert_eq!(Err(QueueFullError), pool.try_execute(|| {}));
+
+        drop(release);
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn drop_oldest_fails_the_dropped_handle() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+
+        let oldest = pool.spawn(|| "oldest");
+        let newest = pool.spawn(|| "newest");
+        drop(release);
+
+        assert!(oldest.join().is_err());
+        assert_eq!("newest", newest.join().unwrap());
+    }
+
+    #[test]
+    fn block_policy_waits_for_room() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Block)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+        pool.execute(|| {});
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(release);
+        });
+
+        // The queue is full, so this only returns after the worker is released
+        let start = Instant::now();
+        assert_eq!(7, pool.spawn(|| 7).join().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn builder_rejects_min_above_max() {
+        assert!(matches!(
+            ThreadPool::builder().min_workers(4).max_workers(2).build(),
+            Err(PoolCreationError::InvalidRange { min: 4, max: 2 })
+        ));
+    }
+
+    #[test]
+    fn elastic_pool_grows_up_to_max() {
+        let pool = ThreadPool::builder()
+            .min_workers(1)
+            .max_workers(3)
+            .build()
+            .unwrap();
+        assert_eq!(1, pool.worker_count());
+
+        // Each busy job makes the next one wait, so a worker is added for it
+        let releases: Vec<_> = (0..3).map(|_| occupy(&pool)).collect();
+        assert_eq!(3, pool.worker_count());
+
+        // At the limit the job waits in the queue instead
+        pool.execute(|| {});
+        assert_eq!(3, pool.worker_count());
+        assert_eq!(1, pool.queued());
+
+        drop(releases);
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn idle_extra_workers_retire() {
+        let pool = ThreadPool::builder()
+            .min_workers(1)
+            .max_workers(4)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let releases: Vec<_> = (0..4).map(|_| occupy(&pool)).collect();
+        assert_eq!(4, pool.worker_count());
+        drop(releases);
+
+        let start = Instant::now();
+        while pool.worker_count() > 1 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, pool.worker_count());
+        // The one that stays still runs jobs
+        assert_eq!(2, pool.spawn(|| 1 + 1).join().unwrap());
+    }
+
+    #[test]
+    fn pool_can_start_without_workers() {
+        let pool = ThreadPool::builder()
+            .min_workers(0)
+            .max_workers(1)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert_eq!(0, pool.worker_count());
+
+        assert_eq!("ran", pool.spawn(|| "ran").join().unwrap());
+        assert_eq!(1, pool.worker_count());
+    }
+
+    #[test]
+    fn handles_are_refused_once_the_pool_is_gone() {
+        let pool = ThreadPool::builder()
+            .min_workers(0)
+            .max_workers(1)
+            .build()
+            .unwrap();
+        let handle = pool.handle();
+        drop(pool);
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        let queued = handle.try_execute_with_priority(Priority::Normal, move || {
+            flag.store(true, Ordering::SeqCst)
+        });
+        assert!(queued.is_err());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn jobs_can_start_more_jobs() {
+        let pool = Arc::new(ThreadPool::build(4).unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        // The inner jobs land in the deque of the worker that starts them,
+        // the idle workers have to steal them from there
+        for _ in 0..4 {
+            let inner = Arc::clone(&pool);
+            let sender = sender.clone();
+            pool.execute(move || {
+                for n in 0..100 {
+                    let sender = sender.clone();
+                    inner.execute(move || sender.send(n).unwrap());
+                }
+            });
+        }
+        drop(sender);
+
+        let total: usize = receiver.iter().take(400).sum();
+        assert_eq!(4 * 4950, total);
+
+        // A worker must not drop the last handle, the pool would wait for itself
+        while Arc::strong_count(&pool) > 1 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn stats_follow_the_jobs() {
+        let pool = ThreadPool::build(1).unwrap();
+        let release = occupy(&pool);
+        pool.execute(|| {});
+
+        let stats = pool.stats();
+        assert_eq!((1, 0, 1), (stats.active, stats.idle, stats.queued));
+        assert_eq!(1, stats.queue_time.count);
+
+        drop(release);
+        assert!(pool.spawn(|| -> u32 { panic!("counted") }).join().is_err());
+
+        let monitor = pool.monitor();
+        drop(pool);
+        let stats = monitor.stats();
+        assert_eq!((2, 1), (stats.completed, stats.panicked));
+        assert_eq!((0, 0), (stats.active, stats.queued));
+        assert_eq!(3, stats.run_time.count);
+    }
+
+    #[test]
+    fn busy_pool_runs_high_priority_jobs_first() {
+        let pool = ThreadPool::build(1).unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let release = occupy(&pool);
+
+        for (n, priority) in [
+            (1, Priority::Low),
+            (2, Priority::Normal),
+            (3, Priority::High),
+        ] {
+            let log = Arc::clone(&log);
+            pool.execute_with_priority(priority, move || log.lock().unwrap().push(n));
+        }
+
+        drop(release);
+        drop(pool);
+        assert_eq!(vec![3, 2, 1], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn handles_queue_jobs_from_jobs() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let handle = pool.handle();
+        let (sender, receiver) = mpsc::channel();
+
+        // A job can't own the pool, but it can queue more jobs through a handle
+        pool.execute(move || {
+            handle.execute_with_priority(Priority::High, move || sender.send(2).unwrap());
+        });
+        assert_eq!(2, receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        let handle = pool.handle();
+        drop(pool);
+        assert_eq!(
+            Err(QueueFullError),
+            handle.try_execute_with_priority(Priority::Normal, || {})
+        );
+    }
+
+    #[test]
+    fn panicking_job_does_not_kill_the_worker() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        let panics = sender.clone();
+        pool.set_panic_handler(move |job_panic| {
+            panics.send(job_panic.message.clone()).unwrap();
+        });
+
+        pool.execute(|| panic!("boom"));
+        pool.execute(move || sender.send("still alive".to_string()).unwrap());
+
+        assert_eq!("boom", receiver.recv().unwrap());
+        assert_eq!("still alive", receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn dead_worker_is_replaced() {
+        let pool = ThreadPool::build(1).unwrap();
+        // A panicking handler is the one panic the worker can't catch
+        pool.set_panic_handler(|_| panic!("the handler failed too"));
+
+        pool.execute(|| panic!("boom"));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || {
+            let name = thread::current().name().map(String::from);
+            sender.send(name).unwrap();
+        });
+
+        // The job ran on a new thread, so the pool did not shrink
+        let name = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(Some("worker-1".to_string()), name);
+        assert_eq!(1, pool.shared.workers().len());
+    }
+
+    #[test]
+    fn drop_after_panics_does_not_panic() {
+        let pool = ThreadPool::build(2).unwrap();
+        let ran = Arc::new(AtomicBool::new(false));
+        pool.set_panic_handler(|_| {});
+
+        for _ in 0..4 {
+            pool.execute(|| panic!("boom"));
+        }
+        let flag = Arc::clone(&ran);
+        pool.execute(move || flag.store(true, Ordering::SeqCst));
+
+        drop(pool);
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn spawn_returns_the_result() {
+        let pool = ThreadPool::build(4).unwrap();
+
+        let handles: Vec<JobHandle<u64>> = (0..8u64)
+            .map(|n| pool.spawn(move || (0..=n * 1000).sum()))
+            .collect();
+
+        for (n, handle) in handles.into_iter().enumerate() {
+            let n = n as u64 * 1000;
+            assert_eq!(n * (n + 1) / 2, handle.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn spawn_returns_the_panic_payload() {
+        let pool = ThreadPool::build(1).unwrap();
+        let called = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&called);
+        pool.set_panic_handler(move |_| flag.store(true, Ordering::SeqCst));
+
+        let handle = pool.spawn(|| -> u32 { panic!("bad input") });
+        let payload = handle.join().unwrap_err();
+
+        assert_eq!(Some(&"bad input"), payload.downcast_ref::<&str>());
+        assert!(!called.load(Ordering::SeqCst));
+        // The worker is still there for the next job
+        assert_eq!(3, pool.spawn(|| 1 + 2).join().unwrap());
+    }
+
+    #[test]
+    fn workers_are_named() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        pool.execute(move || {
+            let name = thread::current().name().map(String::from);
+            sender.send(name).unwrap();
+        });
+
+        assert_eq!(Some("worker-0".to_string()), receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn shutdown_runs_queued_jobs() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..8 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+        assert_eq!(8, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_the_deadline() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| thread::sleep(Duration::from_secs(2)));
+
+        let start = Instant::now();
+        assert!(!pool.shutdown_timeout(Duration::from_millis(100)));
+        assert!(start.elapsed() < Duration::from_secs(1));
     }
 }
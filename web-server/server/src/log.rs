@@ -0,0 +1,86 @@
+// Levelled logging for the server and the pool
+//
+// Lines go to stderr when their level is at or above the one set with
+// `set_level` (Info by default), with the time in front:
+//
+//     2024-05-01T12:00:00.000Z INFO  Listening on http://127.0.0.1:7878
+//
+// The macros work like println!, and skip the formatting when the level is off.
+// This is only the server's own chatter, requests go to the access log.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::SystemTime;
+
+use crate::config::LogLevel;
+use crate::date::format_rfc3339;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Lines below `level` are skipped from now on, for every thread
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// True if a line at `level` would be written
+pub fn enabled(level: LogLevel) -> bool {
+    // Error is the lowest number, so "at least as important" means smaller or equal
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes one line, use the macros instead so the level is checked first
+#[doc(hidden)]
+pub fn write(level: LogLevel, args: fmt::Arguments) {
+    let label = match level {
+        LogLevel::Error => "ERROR",
+        LogLevel::Warn => "WARN",
+        LogLevel::Info => "INFO",
+        LogLevel::Debug => "DEBUG",
+        LogLevel::Trace => "TRACE",
+    };
+    // The line is built first and written at once, so lines from different threads don't mix
+    let line = format!(
+        "{} {:<5} {}\n",
+        format_rfc3339(SystemTime::now()),
+        label,
+        args
+    );
+    // If stderr is gone there is nowhere left to complain to
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// Logs at the given level: `log!(LogLevel::Info, "{} workers", n)`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Trace, $($arg)+) };
+}
@@ -0,0 +1,132 @@
+// Code that runs around every request, whatever route it goes to
+//
+// Middlewares are added to the router and run in the order they were added,
+// each one wrapping the ones after it and the route at the end:
+//
+//     first -> second -> route -> second -> first
+//
+// A middleware gets the request and `Next`, the rest of the chain. It may
+// change the request before passing it on, answer by itself without calling
+// `next.run` at all, or change the response on its way back.
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+
+/// Runs around the handling of every request, see `Router::wrap`
+///
+/// Closures with the same signature as `handle` are middlewares too.
+pub trait Middleware: Send + Sync {
+    /// Answers `request`, usually by calling `next.run(request)` somewhere in between
+    fn handle(&self, request: &mut Request, next: Next) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The middlewares after the current one, and the routes after them
+pub struct Next<'a> {
+    chain: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(chain: &'a [Box<dyn Middleware>], router: &'a Router) -> Next<'a> {
+        Next { chain, router }
+    }
+
+    /// Passes `request` on and returns the response that comes back
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.chain.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(rest, self.router)),
+            None => self.router.dispatch(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Request, Response, Router, Status};
+    use std::sync::{Arc, Mutex};
+
+    fn request(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn middlewares_run_in_order_around_the_route() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut router = Router::new();
+        for name in ["first", "second"] {
+            let calls = Arc::clone(&calls);
+            router.wrap(move |request: &mut Request, next: super::Next| {
+                calls.lock().unwrap().push(format!("{} in", name));
+                let response = next.run(request);
+                calls.lock().unwrap().push(format!("{} out", name));
+                response
+            });
+        }
+        let route_calls = Arc::clone(&calls);
+        router.get("/", move |_, _| {
+            route_calls.lock().unwrap().push("route".to_string());
+            Response::text(Status::Ok, "hi")
+        });
+
+        router.handle(&mut request("/"));
+
+        assert_eq!(
+            vec!["first in", "second in", "route", "second out", "first out"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn middlewares_can_answer_by_themselves() {
+        let mut router = Router::new();
+        router.wrap(|request: &mut Request, next: super::Next| {
+            if request.header("Authorization").is_none() {
+                return Response::text(Status::Unauthorized, "who are you?");
+            }
+            next.run(request)
+        });
+        router.get("/", |_, _| panic!("the route must not run"));
+
+        let response = router.handle(&mut request("/"));
+        assert_eq!(Status::Unauthorized, response.status);
+        assert_eq!("who are you?", body(&response));
+    }
+
+    #[test]
+    fn middlewares_change_requests_and_responses() {
+        let mut router = Router::new();
+        // Old links keep working, and every answer says who sent it
+        router.wrap(|request: &mut Request, next: super::Next| {
+            if let Some(rest) = request.path.strip_prefix("/old") {
+                request.path = format!("/new{}", rest);
+            }
+            next.run(request).with_header("Server", "rust-book-notes")
+        });
+        router.get("/new/:page", |_, params| {
+            Response::text(Status::Ok, params.get("page").unwrap())
+        });
+
+        let response = router.handle(&mut request("/old/about"));
+        assert_eq!("about", body(&response));
+        assert_eq!(Some("rust-book-notes"), response.headers.get("Server"));
+        // 404s go through the chain too
+        let response = router.handle(&mut request("/missing"));
+        assert_eq!(Status::NotFound, response.status);
+        assert_eq!(Some("rust-book-notes"), response.headers.get("Server"));
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::Path;
+
+/// Picks the `Content-Type` of a file from its extension
+///
+/// Unknown extensions get `application/octet-stream`, which tells the
+/// browser to download the file instead of guessing what it is.
+pub fn from_path(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => from_extension(ext),
+        None => "application/octet-stream",
+    }
+}
+
+pub fn from_extension(extension: &str) -> &'static str {
+    match extension.to_ascii_lowercase().as_str() {
+        // Text
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        // Images
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        // Fonts
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        // Audio and video
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        // Everything else
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_and_unknown_extensions() {
+        assert_eq!(
+            "text/html; charset=utf-8",
+            from_path(Path::new("index.html"))
+        );
+        assert_eq!("image/png", from_path(Path::new("img/Logo.PNG")));
+        assert_eq!("application/octet-stream", from_path(Path::new("Makefile")));
+        assert_eq!("application/octet-stream", from_extension("xyz"));
+    }
+}
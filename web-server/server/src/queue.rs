@@ -0,0 +1,890 @@
+// The scheduler between `execute` and the workers
+//
+// The first version was one `Mutex<mpsc::Receiver>` that every worker locked to
+// get a job, so with many short jobs the workers spent their time waiting for
+// that lock. Now every worker has its own deque and only looks elsewhere when
+// it runs dry:
+//
+// - jobs from outside the pool go to the global injector queue
+// - jobs a job starts (execute called on a worker thread) go to that worker's deque
+// - a worker takes from its own deque first, then grabs a batch from the injector,
+//   and last steals from the other workers
+//
+// The deques come from crossbeam-deque, they are lock-free, which is the whole point.
+//
+// Every priority is a lane with its own injector and its own deque per worker.
+// Workers look through the lanes from high to low, so a slow job in the normal
+// lane doesn't hold up a health check in the high one. To keep a busy high lane
+// from starving the others, a lane that has waited longer than the starvation
+// timeout for a worker is looked at first.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+// How a job ended, as far as the pool's counters are concerned
+pub(crate) enum Outcome {
+    Completed,
+    // The job caught its own panic (spawn hands it to the JobHandle)
+    Panicked,
+}
+
+// The task is a trait object that holds the type of closure that execute will execute
+type Task = Box<dyn FnOnce() -> Outcome + Send + 'static>;
+
+/// A closure waiting in the queue, with the time it was queued
+pub(crate) struct Job {
+    pub(crate) task: Task,
+    pub(crate) queued_at: Instant,
+    pub(crate) priority: Priority,
+    // False for jobs from push_unbounded, DropOldest must leave those alone
+    droppable: bool,
+}
+
+impl Job {
+    pub(crate) fn new<F>(f: F) -> Job
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Job::with_outcome(move || {
+            f();
+            Outcome::Completed
+        })
+    }
+
+    pub(crate) fn with_outcome<F>(f: F) -> Job
+    where
+        F: FnOnce() -> Outcome + Send + 'static,
+    {
+        Job {
+            task: Box::new(f),
+            queued_at: Instant::now(),
+            priority: Priority::Normal,
+            droppable: true,
+        }
+    }
+
+    pub(crate) fn with_priority(mut self, priority: Priority) -> Job {
+        self.priority = priority;
+        self
+    }
+}
+
+// The task can't be printed, the rest can
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("queued_at", &self.queued_at)
+            .field("priority", &self.priority)
+            .field("droppable", &self.droppable)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Which lane a job waits in, workers take high priority jobs first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    /// Only taken when nothing else is waiting, or once it has waited for the starvation timeout
+    Low,
+}
+
+impl Priority {
+    pub fn parse(priority: &str) -> Option<Priority> {
+        match priority.to_ascii_lowercase().as_str() {
+            "high" => Some(Priority::High),
+            "normal" => Some(Priority::Normal),
+            "low" => Some(Priority::Low),
+            _ => None,
+        }
+    }
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+const LANES: usize = 3;
+
+/// What the pool does with a new job when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// The caller waits until a worker takes a job off the queue
+    #[default]
+    Block,
+    /// The new job is refused and `try_execute` returns an error
+    Reject,
+    /// A waiting job is thrown away to make room: the oldest of the lowest
+    /// priority that has any, so a new Low job goes before a High one that
+    /// has waited for a while. Jobs spawned in a `ThreadPool::scope` are
+    /// never thrown away, the scope is waiting for them.
+    DropOldest,
+}
+
+/// The job was not queued because the queue is full (or the pool is shutting down)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFullError;
+
+impl fmt::Display for QueueFullError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the job queue is full")
+    }
+}
+
+impl Error for QueueFullError {}
+
+/// What a worker gets from `JobQueue::pop`
+pub(crate) enum Pop {
+    Job(Job),
+    /// Nothing arrived in time, the worker may retire
+    TimedOut,
+    /// The pool is shutting down and the queue is empty
+    Closed,
+}
+
+// The calling thread's own deque, set while it is a worker of some queue
+struct Local {
+    // Which queue the deque belongs to, a job may use another pool
+    queue: *const JobQueue,
+    worker_id: usize,
+    // One deque per lane, indexed by Priority::lane
+    deques: [Worker<Job>; LANES],
+}
+
+thread_local! {
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+// The shared part of one priority
+struct Lane {
+    injector: Injector<Job>,
+    // Jobs of this priority pushed and not taken yet
+    queued: AtomicUsize,
+    // When a worker last took a job from here, or when the lane stopped being empty,
+    // in microseconds since the queue was created
+    waiting_since: AtomicU64,
+}
+
+pub(crate) struct JobQueue {
+    lanes: [Lane; LANES],
+    // The stealers of every registered worker, one per lane, with the worker's id
+    stealers: RwLock<Vec<(usize, [Stealer<Job>; LANES])>>,
+    // Jobs pushed and not taken yet, in any lane
+    // It is reserved before the push, so it can be a little ahead of what is visible
+    queued: AtomicUsize,
+    // Workers that found nothing and are about to sleep or sleeping
+    idle: AtomicUsize,
+    closed: AtomicBool,
+    // Idle workers sleep here, only the sleeping needs a lock
+    sleep: Mutex<()>,
+    available: Condvar,
+    // Producers blocked by a full queue (Block policy) sleep here
+    space_lock: Mutex<()>,
+    space: Condvar,
+    blocked: AtomicUsize,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    starvation_timeout: Duration,
+    created: Instant,
+}
+
+// How long pop waits before looking again for a job that is queued but can't be taken yet
+const BUSY_RETRY: Duration = Duration::from_millis(1);
+
+// There is no data behind these locks, they only pair with the condvars
+fn lock(mutex: &Mutex<()>) -> MutexGuard<'_, ()> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl JobQueue {
+    /// `capacity` of `None` means the queue can grow without limit
+    pub(crate) fn new(
+        capacity: Option<usize>,
+        policy: OverflowPolicy,
+        starvation_timeout: Duration,
+    ) -> JobQueue {
+        let lane = || Lane {
+            injector: Injector::new(),
+            queued: AtomicUsize::new(0),
+            waiting_since: AtomicU64::new(0),
+        };
+        JobQueue {
+            lanes: [lane(), lane(), lane()],
+            stealers: RwLock::new(Vec::new()),
+            queued: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            available: Condvar::new(),
+            space_lock: Mutex::new(()),
+            space: Condvar::new(),
+            blocked: AtomicUsize::new(0),
+            capacity,
+            policy,
+            starvation_timeout,
+            created: Instant::now(),
+        }
+    }
+
+    /// Gives the calling thread its own deque, called once by every worker thread
+    pub(crate) fn register(&self, worker_id: usize) {
+        let deques = [Worker::new_fifo(), Worker::new_fifo(), Worker::new_fifo()];
+        let stealers = [
+            deques[0].stealer(),
+            deques[1].stealer(),
+            deques[2].stealer(),
+        ];
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((worker_id, stealers));
+
+        LOCAL.with(|local| {
+            *local.borrow_mut() = Some(Local {
+                queue: self,
+                worker_id,
+                deques,
+            })
+        });
+    }
+
+    /// Hands the calling thread's jobs back to the others before the thread goes away
+    pub(crate) fn unregister(&self) {
+        let Some(local) = LOCAL.with(|local| local.borrow_mut().take()) else {
+            return;
+        };
+
+        // Moved first and unlisted after, so the jobs can always be found by someone
+        let mut moved = false;
+        for (deque, lane) in local.deques.iter().zip(&self.lanes) {
+            while let Some(job) = deque.pop() {
+                lane.injector.push(job);
+                moved = true;
+            }
+        }
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(id, _)| *id != local.worker_id);
+
+        if moved {
+            self.wake_workers(true);
+        }
+    }
+
+    /// Queues `job`, or hands it back when the queue is full or closed
+    pub(crate) fn push(&self, job: Job) -> Result<(), Job> {
+        let job = match self.push_local(job) {
+            Ok(()) => return Ok(()),
+            Err(job) => job,
+        };
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(job);
+        }
+
+        let mut dropped = None;
+        if !self.reserve() {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    if !self.wait_for_space() {
+                        return Err(job);
+                    }
+                }
+                OverflowPolicy::Reject => return Err(job),
+                OverflowPolicy::DropOldest => {
+                    // Jobs that must not be dropped are put aside and queued again
+                    let mut kept = Vec::new();
+                    loop {
+                        match self.steal_oldest() {
+                            Some(oldest) if !oldest.droppable => kept.push(oldest),
+                            // The new job takes over the slot of the one we drop
+                            Some(oldest) => {
+                                self.lanes[oldest.priority.lane()]
+                                    .queued
+                                    .fetch_sub(1, Ordering::SeqCst);
+                                dropped = Some(oldest);
+                                break;
+                            }
+                            // A worker took the last one in the meantime, so now there is room
+                            None if self.reserve() => break,
+                            // Nothing but kept jobs left, the new one goes over the capacity
+                            None if !kept.is_empty() => {
+                                self.queued.fetch_add(1, Ordering::SeqCst);
+                                break;
+                            }
+                            None => {}
+                        }
+                    }
+                    // Still counted in queued and their lane, they were never taken
+                    for job in kept {
+                        self.lanes[job.priority.lane()].injector.push(job);
+                    }
+                }
+            }
+        }
+
+        // Everything else goes to the injector of its lane
+        self.lane_pushed(job.priority);
+        self.lanes[job.priority.lane()].injector.push(job);
+        self.wake_workers(false);
+
+        // Dropping a job runs the destructors of everything it captured
+        // (closing a TcpStream, failing a JobHandle), which is done after the push
+        drop(dropped);
+        Ok(())
+    }
+
+    /// Queues `job` even on a full queue, and DropOldest never drops it later
+    ///
+    /// It is only handed back when the queue is closed. This is for jobs
+    /// someone waits for anyway, like the scoped ones.
+    pub(crate) fn push_unbounded(&self, mut job: Job) -> Result<(), Job> {
+        job.droppable = false;
+        let job = match self.push_local(job) {
+            Ok(()) => return Ok(()),
+            Err(job) => job,
+        };
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(job);
+        }
+
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.lane_pushed(job.priority);
+        self.lanes[job.priority.lane()].injector.push(job);
+        self.wake_workers(false);
+        Ok(())
+    }
+
+    // On a worker thread of this queue the job stays local, so it is likely
+    // to run on a warm cache, anywhere else it is handed back
+    //
+    // Those jobs are started by a running job, so the pool took on that work
+    // already and they are never refused, not even when closing. Blocking a
+    // worker on a full queue could also leave nobody to make room.
+    fn push_local(&self, job: Job) -> Result<(), Job> {
+        let job = LOCAL.with(|local| match local.borrow().as_ref() {
+            Some(local) if std::ptr::eq(local.queue, self) => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                self.lane_pushed(job.priority);
+                local.deques[job.priority.lane()].push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        match job {
+            Some(job) => Err(job),
+            None => {
+                self.wake_workers(false);
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits at most `timeout` for a job
+    pub(crate) fn pop(&self, timeout: Duration) -> Pop {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(job) = self.find_job() {
+                self.taken(job.priority);
+                return Pop::Job(job);
+            }
+
+            let guard = lock(&self.sleep);
+            // idle goes up before queued is checked and push does the opposite,
+            // so at least one of the two sees the other and no wake-up is lost
+            self.idle.fetch_add(1, Ordering::SeqCst);
+
+            let pop = if self.queued.load(Ordering::SeqCst) > 0 {
+                // Something is queued but was not found, it is being pushed or moved.
+                // Trying again right away would burn a core until then, the push
+                // wakes us up when it's done and the timeout covers the rest.
+                let _guard = self
+                    .available
+                    .wait_timeout(guard, BUSY_RETRY)
+                    .unwrap_or_else(PoisonError::into_inner);
+                None
+            } else if self.closed.load(Ordering::SeqCst) {
+                Some(Pop::Closed)
+            } else {
+                let now = Instant::now();
+                if now >= deadline {
+                    Some(Pop::TimedOut)
+                } else {
+                    let _guard = self
+                        .available
+                        .wait_timeout(guard, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    None
+                }
+            };
+
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+            if let Some(pop) = pop {
+                return pop;
+            }
+        }
+    }
+
+    /// Takes a job if one can be found right now, without waiting or counting as idle
+    pub(crate) fn try_pop(&self) -> Option<Job> {
+        let job = self.find_job()?;
+        self.taken(job.priority);
+        Some(job)
+    }
+
+    /// The id the calling thread registered with, if it is a worker of this queue
+    pub(crate) fn worker_id(&self) -> Option<usize> {
+        LOCAL.with(|local| match local.borrow().as_ref() {
+            Some(local) if std::ptr::eq(local.queue, self) => Some(local.worker_id),
+            _ => None,
+        })
+    }
+
+    /// Stops accepting jobs and wakes everyone up, the jobs already queued still run
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        {
+            let _guard = lock(&self.sleep);
+            self.available.notify_all();
+        }
+        let _guard = lock(&self.space_lock);
+        self.space.notify_all();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// True when more jobs are waiting than there are idle workers to take them
+    pub(crate) fn is_backed_up(&self) -> bool {
+        self.len() > self.idle.load(Ordering::SeqCst)
+    }
+
+    // Takes a slot in the queue if there is one
+    fn reserve(&self) -> bool {
+        match self.capacity {
+            None => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(capacity) => self
+                .queued
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                    (queued < capacity).then_some(queued + 1)
+                })
+                .is_ok(),
+        }
+    }
+
+    // False if the queue was closed while we waited
+    fn wait_for_space(&self) -> bool {
+        let mut guard = lock(&self.space_lock);
+        // Same trick as idle and queued, taken() checks blocked after freeing a slot
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        let reserved = loop {
+            if self.closed.load(Ordering::SeqCst) {
+                break false;
+            }
+            if self.reserve() {
+                break true;
+            }
+            guard = self
+                .space
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        };
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+        reserved
+    }
+
+    // Counted before the job can be found, so taken() never goes below 0
+    fn lane_pushed(&self, priority: Priority) {
+        let lane = &self.lanes[priority.lane()];
+        // A lane that was empty hasn't been waiting for anything until now
+        if lane.queued.fetch_add(1, Ordering::SeqCst) == 0 {
+            lane.waiting_since.store(self.now(), Ordering::SeqCst);
+        }
+    }
+
+    // Called for every job a worker takes
+    fn taken(&self, priority: Priority) {
+        let lane = &self.lanes[priority.lane()];
+        lane.queued.fetch_sub(1, Ordering::SeqCst);
+        lane.waiting_since.store(self.now(), Ordering::SeqCst);
+
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.space_lock);
+            self.space.notify_one();
+        }
+    }
+
+    fn wake_workers(&self, all: bool) {
+        if self.idle.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        // Taking the lock means the worker is either still checking queued
+        // (and will see the job) or already waiting (and gets the notification)
+        let _guard = lock(&self.sleep);
+        if all {
+            self.available.notify_all();
+        } else {
+            self.available.notify_one();
+        }
+    }
+
+    fn now(&self) -> u64 {
+        u64::try_from(self.created.elapsed().as_micros()).unwrap_or(u64::MAX)
+    }
+
+    // High to low, except that a lane waiting longer than the starvation timeout goes first
+    fn lane_order(&self) -> [Priority; LANES] {
+        let now = self.now();
+        let limit = u64::try_from(self.starvation_timeout.as_micros()).unwrap_or(u64::MAX);
+        let starving = |priority: Priority| {
+            let lane = &self.lanes[priority.lane()];
+            lane.queued.load(Ordering::SeqCst) > 0
+                && now.saturating_sub(lane.waiting_since.load(Ordering::SeqCst)) >= limit
+        };
+
+        if starving(Priority::Low) {
+            [Priority::Low, Priority::High, Priority::Normal]
+        } else if starving(Priority::Normal) {
+            [Priority::Normal, Priority::High, Priority::Low]
+        } else {
+            [Priority::High, Priority::Normal, Priority::Low]
+        }
+    }
+
+    // In every lane: own deque first, then a batch from the injector, then the other workers
+    fn find_job(&self) -> Option<Job> {
+        LOCAL.with(|local| {
+            let local = local.borrow();
+            let deques = local
+                .as_ref()
+                .filter(|local| std::ptr::eq(local.queue, self))
+                .map(|local| &local.deques);
+
+            loop {
+                // Retry means we lost a race with another thread, not that it's empty
+                let mut retry = false;
+                for priority in self.lane_order() {
+                    let lane = priority.lane();
+                    let deque = deques.map(|deques| &deques[lane]);
+                    if let Some(job) = deque.and_then(Worker::pop) {
+                        return Some(job);
+                    }
+
+                    let steal = match deque {
+                        Some(deque) => self.lanes[lane].injector.steal_batch_and_pop(deque),
+                        None => self.lanes[lane].injector.steal(),
+                    }
+                    .or_else(|| self.steal_from_workers(lane));
+
+                    match steal {
+                        Steal::Success(job) => return Some(job),
+                        Steal::Empty => {}
+                        Steal::Retry => retry = true,
+                    }
+                }
+                if !retry {
+                    return None;
+                }
+            }
+        })
+    }
+
+    fn steal_from_workers(&self, lane: usize) -> Steal<Job> {
+        let stealers = self.stealers.read().unwrap_or_else(PoisonError::into_inner);
+        stealers
+            .iter()
+            .map(|(_, stealers)| stealers[lane].steal())
+            .collect()
+    }
+
+    // The lowest priority goes first, in a lane the injector has the oldest jobs,
+    // after that the front of every deque
+    fn steal_oldest(&self) -> Option<Job> {
+        loop {
+            let mut retry = false;
+            for priority in [Priority::Low, Priority::Normal, Priority::High] {
+                let lane = priority.lane();
+                match self.lanes[lane]
+                    .injector
+                    .steal()
+                    .or_else(|| self.steal_from_workers(lane))
+                {
+                    Steal::Success(job) => return Some(job),
+                    Steal::Empty => {}
+                    Steal::Retry => retry = true,
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    const WAIT: Duration = Duration::from_secs(5);
+    // Long enough that no lane starves during a test
+    const PATIENT: Duration = Duration::from_secs(60);
+
+    // A job that adds its number to `log` when it runs
+    fn job(log: &Arc<Mutex<Vec<u32>>>, n: u32) -> Job {
+        let log = Arc::clone(log);
+        Job::new(move || log.lock().unwrap().push(n))
+    }
+
+    fn run_all(queue: &JobQueue) {
+        queue.close();
+        while let Pop::Job(job) = queue.pop(WAIT) {
+            (job.task)();
+        }
+    }
+
+    #[test]
+    fn reject_refuses_jobs_when_full() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::Reject, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        assert!(queue.push(job(&log, 1)).is_ok());
+        assert!(queue.push(job(&log, 2)).is_ok());
+        assert!(queue.push(job(&log, 3)).is_err());
+
+        run_all(&queue);
+        assert_eq!(vec![1, 2], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::DropOldest, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        for n in 1..=4 {
+            assert!(queue.push(job(&log, n)).is_ok());
+        }
+
+        run_all(&queue);
+        assert_eq!(vec![3, 4], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn block_waits_for_space() {
+        let queue = Arc::new(JobQueue::new(Some(1), OverflowPolicy::Block, PATIENT));
+        let pushed = Arc::new(AtomicUsize::new(0));
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            let pushed = Arc::clone(&pushed);
+            thread::spawn(move || {
+                for _ in 0..2 {
+                    queue.push(Job::new(|| {})).unwrap();
+                    pushed.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        // The second push is stuck until a job is taken
+        assert_eq!(1, pushed.load(Ordering::SeqCst));
+
+        assert!(matches!(queue.pop(WAIT), Pop::Job(_)));
+        producer.join().unwrap();
+        assert_eq!(2, pushed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn closed_queue_drains_then_ends() {
+        let queue = JobQueue::new(None, OverflowPolicy::Block, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        queue.push(job(&log, 1)).unwrap();
+
+        queue.close();
+        assert!(queue.push(job(&log, 2)).is_err());
+        assert!(matches!(queue.pop(WAIT), Pop::Job(_)));
+        assert!(matches!(queue.pop(WAIT), Pop::Closed));
+    }
+
+    #[test]
+    fn pop_times_out_and_counts_idle_workers() {
+        let queue = Arc::new(JobQueue::new(None, OverflowPolicy::Block, PATIENT));
+        assert!(matches!(
+            queue.pop(Duration::from_millis(20)),
+            Pop::TimedOut
+        ));
+
+        let waiter = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || matches!(queue.pop(WAIT), Pop::Job(_)))
+        };
+        thread::sleep(Duration::from_millis(100));
+
+        // The waiter took the first job, nobody is left to take the second one
+        queue.push(Job::new(|| {})).unwrap();
+        assert!(waiter.join().unwrap());
+        queue.push(Job::new(|| {})).unwrap();
+        assert!(queue.is_backed_up());
+    }
+
+    #[test]
+    fn workers_push_locally_and_others_steal() {
+        let queue = Arc::new(JobQueue::new(None, OverflowPolicy::Block, PATIENT));
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // A registered thread keeps its jobs in its own deque
+        queue.register(0);
+        queue.push(job(&log, 1)).unwrap();
+        assert!(queue.lanes[Priority::Normal.lane()].injector.is_empty());
+
+        // ...where another thread can steal them
+        let thief = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || match queue.pop(WAIT) {
+                Pop::Job(job) => {
+                    (job.task)();
+                }
+                _ => panic!("nothing to steal"),
+            })
+        };
+        thief.join().unwrap();
+        assert_eq!(vec![1], *log.lock().unwrap());
+        queue.unregister();
+    }
+
+    #[test]
+    fn unregister_hands_jobs_back() {
+        let queue = JobQueue::new(None, OverflowPolicy::Block, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        queue.register(0);
+        queue.push(job(&log, 1)).unwrap();
+        queue.push(job(&log, 2)).unwrap();
+        queue.unregister();
+
+        assert_eq!(2, queue.lanes[Priority::Normal.lane()].injector.len());
+        run_all(&queue);
+        assert_eq!(vec![1, 2], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let queue = JobQueue::new(None, OverflowPolicy::Block, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        queue
+            .push(job(&log, 1).with_priority(Priority::Low))
+            .unwrap();
+        queue.push(job(&log, 2)).unwrap();
+        queue
+            .push(job(&log, 3).with_priority(Priority::High))
+            .unwrap();
+        queue.push(job(&log, 4)).unwrap();
+
+        run_all(&queue);
+        assert_eq!(vec![3, 2, 4, 1], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn starving_jobs_get_a_turn() {
+        let queue = JobQueue::new(None, OverflowPolicy::Block, Duration::from_millis(50));
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        queue
+            .push(job(&log, 1).with_priority(Priority::Low))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        queue
+            .push(job(&log, 2).with_priority(Priority::High))
+            .unwrap();
+        queue
+            .push(job(&log, 3).with_priority(Priority::High))
+            .unwrap();
+
+        // The low job waited past the timeout, the high ones just arrived
+        run_all(&queue);
+        assert_eq!(vec![1, 2, 3], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_drops_low_priority_first() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::DropOldest, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        queue.push(job(&log, 1)).unwrap();
+        queue
+            .push(job(&log, 2).with_priority(Priority::Low))
+            .unwrap();
+        queue.push(job(&log, 3)).unwrap();
+
+        run_all(&queue);
+        assert_eq!(vec![1, 3], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_goes_by_priority_before_age() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::DropOldest, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        queue
+            .push(job(&log, 1).with_priority(Priority::High))
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+        queue
+            .push(job(&log, 2).with_priority(Priority::Low))
+            .unwrap();
+        queue.push(job(&log, 3)).unwrap();
+
+        // The High job is the oldest, but the newer Low one makes room
+        run_all(&queue);
+        assert_eq!(vec![1, 3], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_leaves_unbounded_jobs_alone() {
+        let queue = JobQueue::new(Some(2), OverflowPolicy::DropOldest, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        queue.push_unbounded(job(&log, 1)).unwrap();
+        queue.push_unbounded(job(&log, 2)).unwrap();
+        // Nothing can be dropped, so this one goes over the capacity
+        queue.push(job(&log, 3)).unwrap();
+        queue.push(job(&log, 4)).unwrap();
+
+        run_all(&queue);
+        assert_eq!(vec![1, 2, 4], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn jobs_from_workers_are_never_refused() {
+        let queue = JobQueue::new(Some(1), OverflowPolicy::Reject, PATIENT);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        queue.push(job(&log, 1)).unwrap();
+
+        queue.register(0);
+        queue.push(job(&log, 2)).unwrap();
+        queue.close();
+        queue.push(job(&log, 3)).unwrap();
+        queue.unregister();
+
+        run_all(&queue);
+        assert_eq!(vec![1, 2, 3], *log.lock().unwrap());
+    }
+}
@@ -0,0 +1,260 @@
+// Byte ranges, so a client can fetch part of a file (RFC 9110, section 14)
+//
+// A download that broke off resumes with "Range: bytes=1000-", a video player
+// jumps around with many small ranges. One range is answered with just those
+// bytes, several with a multipart/byteranges body where each part has its own
+// `Content-Range` header.
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// More ranges than this in one request is not a download, it's an attack
+const MAX_RANGES: usize = 100;
+
+/// What a `Range` header asks for, given the size of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges {
+    /// The header is malformed, uses a unit other than bytes or asks for too
+    /// much, the whole file is sent
+    Ignored,
+    /// Every range starts after the end of the file, the answer is a 416
+    Unsatisfiable,
+    /// Inclusive (first, last) byte positions, sorted and without overlaps
+    Satisfiable(Vec<(u64, u64)>),
+}
+
+/// Parses a header like "bytes=0-499, 1000-, -200" for a file of `len` bytes
+///
+/// Overlapping and adjacent ranges are merged, a client gets no byte twice.
+pub fn parse(header: &str, len: u64) -> Ranges {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return Ranges::Ignored;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ranges::Ignored;
+    }
+
+    let mut ranges = Vec::new();
+    for (count, spec) in specs.split(',').map(str::trim).enumerate() {
+        if count == MAX_RANGES {
+            return Ranges::Ignored;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return Ranges::Ignored;
+        };
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            // "500-999"
+            (Ok(first), Ok(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+            // "500-", up to the end
+            (Ok(first), Err(_)) if last.is_empty() => (first, len.saturating_sub(1)),
+            // "-200", the last 200 bytes
+            (Err(_), Ok(suffix)) if first.is_empty() => {
+                if suffix == 0 {
+                    continue;
+                }
+                (len.saturating_sub(suffix), len.saturating_sub(1))
+            }
+            _ => return Ranges::Ignored,
+        };
+        // A range that starts past the end is left out, the others may still be fine
+        if range.0 < len {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1 + 1 => previous.1 = previous.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    Ranges::Satisfiable(merged)
+}
+
+/// A multipart/byteranges body, read from `file` while it is being sent
+pub struct Multipart {
+    boundary: String,
+    pieces: VecDeque<Piece>,
+    len: u64,
+}
+
+enum Piece {
+    Text(Cursor<Vec<u8>>),
+    // Where the next byte comes from and how many are left
+    File { position: u64, left: u64 },
+}
+
+impl Multipart {
+    /// The parts for `ranges` of a file of `len` bytes and type `content_type`
+    pub fn new(ranges: &[(u64, u64)], len: u64, content_type: &str) -> Multipart {
+        let boundary = boundary();
+        let mut pieces = VecDeque::new();
+        let mut total = 0;
+
+        for (i, &(first, last)) in ranges.iter().enumerate() {
+            // The CRLF before a boundary belongs to the boundary, not to the part
+            let head = format!(
+                "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                if i == 0 { "" } else { "\r\n" },
+                boundary,
+                content_type,
+                first,
+                last,
+                len
+            );
+            total += head.len() as u64 + (last - first + 1);
+            pieces.push_back(Piece::Text(Cursor::new(head.into_bytes())));
+            pieces.push_back(Piece::File {
+                position: first,
+                left: last - first + 1,
+            });
+        }
+        let end = format!("\r\n--{}--\r\n", boundary);
+        total += end.len() as u64;
+        pieces.push_back(Piece::Text(Cursor::new(end.into_bytes())));
+
+        Multipart {
+            boundary,
+            pieces,
+            len: total,
+        }
+    }
+
+    /// For the `Content-Type` of the response: `multipart/byteranges; boundary=...`
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// The size of the whole body, for `Content-Length`
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The body, reading the parts from `file` as it goes
+    pub fn reader<F: Read + Seek>(self, file: F) -> MultipartReader<F> {
+        MultipartReader {
+            file,
+            pieces: self.pieces,
+        }
+    }
+}
+
+/// Reads the body of a `Multipart`
+pub struct MultipartReader<F> {
+    file: F,
+    pieces: VecDeque<Piece>,
+}
+
+impl<F: Read + Seek> Read for MultipartReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(piece) = self.pieces.front_mut() {
+            let read = match piece {
+                Piece::Text(text) => text.read(buf)?,
+                Piece::File { position, left } if *left > 0 => {
+                    // The parts may be anywhere in the file, so every read seeks first
+                    self.file.seek(SeekFrom::Start(*position))?;
+                    let max = buf.len().min(*left as usize);
+                    let read = self.file.read(&mut buf[..max])?;
+                    if read == 0 {
+                        // The file got shorter since we looked at it
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *position += read as u64;
+                    *left -= read as u64;
+                    read
+                }
+                Piece::File { .. } => 0,
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.pieces.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+// Something that doesn't show up in the file, or only with very bad luck
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:08x}", nanos, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_form() {
+        use Ranges::*;
+        assert_eq!(Satisfiable(vec![(0, 499)]), parse("bytes=0-499", 1000));
+        assert_eq!(Satisfiable(vec![(900, 999)]), parse("bytes=900-", 1000));
+        assert_eq!(Satisfiable(vec![(800, 999)]), parse("bytes=-200", 1000));
+        // Past the end is cut to the end, a suffix longer than the file is all of it
+        assert_eq!(Satisfiable(vec![(500, 999)]), parse("bytes=500-5000", 1000));
+        assert_eq!(Satisfiable(vec![(0, 999)]), parse("bytes=-5000", 1000));
+        assert_eq!(
+            Satisfiable(vec![(0, 9), (100, 199)]),
+            parse("Bytes = 100-199, 0-9, 5000-", 1000)
+        );
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            Ranges::Satisfiable(vec![(0, 299), (500, 599)]),
+            parse("bytes=0-99,50-199,200-299,500-599,550-", 600)
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_and_ignored() {
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=1000-", 1000));
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=-0", 1000));
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=0-", 0));
+        assert_eq!(Ranges::Ignored, parse("items=0-1", 1000));
+        assert_eq!(Ranges::Ignored, parse("bytes=5-1", 1000));
+        assert_eq!(Ranges::Ignored, parse("bytes=abc", 1000));
+        assert_eq!(Ranges::Ignored, parse("bytes=0-1,,2-3", 1000));
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(Ranges::Ignored, parse(&format!("bytes={}", many), 1000));
+    }
+
+    #[test]
+    fn multipart_body_matches_its_length() {
+        let file = Cursor::new(b"0123456789abcdefghij".to_vec());
+        let multipart = Multipart::new(&[(0, 2), (15, 19)], 20, "text/plain");
+        let boundary = multipart.boundary().to_string();
+        let len = multipart.len();
+
+        let mut body = String::new();
+        multipart.reader(file).read_to_string(&mut body).unwrap();
+
+        assert_eq!(len, body.len() as u64);
+        assert_eq!(
+            format!(
+                "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/20\r\n\r\n012\
+                 \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 15-19/20\r\n\r\nfghij\
+                 \r\n--{b}--\r\n",
+                b = boundary
+            ),
+            body
+        );
+    }
+}
@@ -0,0 +1,167 @@
+// Sending plain HTTP clients over to HTTPS, as a middleware
+//
+// Someone who types the address without "https://" lands on the plain port
+// first. With HTTPS set up the plain listeners only answer with a redirect to
+// the same host and path on the HTTPS port, nothing is served unencrypted.
+
+use crate::middleware::{Middleware, Next};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// Answers every request with a redirect to the same URL over HTTPS
+///
+/// Nothing after it in the chain runs. GET and HEAD get a 301, other methods
+/// a 308, which tells the client to send the same method and body again.
+///
+/// ```
+/// use server::{HttpsRedirect, Router};
+///
+/// let mut plain = Router::new();
+/// plain.wrap(HttpsRedirect::new(8443));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsRedirect {
+    port: u16,
+}
+
+impl HttpsRedirect {
+    /// `port` is where the HTTPS listener is, 443 is left out of the URLs
+    pub fn new(port: u16) -> HttpsRedirect {
+        HttpsRedirect { port }
+    }
+
+    /// The HTTPS address of `request`, None if its `Host` header is missing or odd
+    ///
+    /// The same goes for a path or query that can't go into a URL as they are,
+    /// the parser refuses most of those already but a `Request` can also be
+    /// built by hand.
+    pub fn location(&self, request: &Request) -> Option<String> {
+        let host = request.header("Host")?;
+        // Whatever port the plain request went to, "[::1]:80" is an IPv6 address and a port
+        let name = match host.strip_prefix('[') {
+            Some(rest) => &host[..rest.find(']')? + 2],
+            None => host.split(':').next().unwrap_or(""),
+        };
+        if name.is_empty() || name.contains(|c: char| c.is_control() || "/?#@\\ ".contains(c)) {
+            return None;
+        }
+        // Whitespace or a control character would break the header, "//evil.test"
+        // as the path would change the host
+        let unsafe_char = |c: char| c.is_control() || c.is_whitespace();
+        if !request.path.starts_with('/')
+            || request.path.starts_with("//")
+            || request
+                .path
+                .contains(|c: char| unsafe_char(c) || c == '?' || c == '#')
+        {
+            return None;
+        }
+        if let Some(query) = &request.query {
+            if query.contains(|c: char| unsafe_char(c) || c == '#') {
+                return None;
+            }
+        }
+
+        let mut location = format!("https://{}", name);
+        if self.port != 443 {
+            location.push_str(&format!(":{}", self.port));
+        }
+        location.push_str(&request.path);
+        if let Some(query) = &request.query {
+            location.push('?');
+            location.push_str(query);
+        }
+        Some(location)
+    }
+}
+
+impl Middleware for HttpsRedirect {
+    fn handle(&self, request: &mut Request, _next: Next) -> Response {
+        let Some(location) = self.location(request) else {
+            return Response::text(Status::BadRequest, "a valid Host header is needed");
+        };
+        let status = match request.method {
+            Method::Get | Method::Head => Status::MovedPermanently,
+            _ => Status::PermanentRedirect,
+        };
+        Response::text(status, format!("Moved to {}\n", location))
+            .with_header("Location", &location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+
+    fn redirect(port: u16, raw: &str) -> Response {
+        let mut router = Router::new();
+        router.wrap(HttpsRedirect::new(port));
+        router.get("/*path", |_, _| panic!("nothing is served over plain HTTP"));
+        router.handle(&mut Request::read_from(&mut raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn keeps_the_host_path_and_query() {
+        let response = redirect(
+            8443,
+            "GET /docs/a.html?page=2 HTTP/1.1\r\nHost: example.org:8080\r\n\r\n",
+        );
+        assert_eq!(Status::MovedPermanently, response.status);
+        assert_eq!(
+            Some("https://example.org:8443/docs/a.html?page=2"),
+            response.headers.get("Location")
+        );
+
+        let response = redirect(443, "POST /form HTTP/1.1\r\nHost: [::1]:80\r\n\r\n");
+        assert_eq!(Status::PermanentRedirect, response.status);
+        assert_eq!(Some("https://[::1]/form"), response.headers.get("Location"));
+    }
+
+    #[test]
+    fn needs_a_usable_host() {
+        for raw in [
+            "GET / HTTP/1.0\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: evil.test/x\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: [::1\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: evil\ttest\r\n\r\n",
+        ] {
+            assert_eq!(Status::BadRequest, redirect(443, raw).status, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn refuses_what_does_not_fit_in_a_url() {
+        let redirect = HttpsRedirect::new(443);
+        let request = |host: &str, path: &str, query: Option<&str>| {
+            let mut request = Request::read_from(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+            request.headers.insert("Host", host);
+            request.path = path.to_string();
+            request.query = query.map(str::to_string);
+            request
+        };
+
+        assert_eq!(
+            Some("https://example.org/a?b=c".to_string()),
+            redirect.location(&request("example.org", "/a", Some("b=c")))
+        );
+        for (host, path, query) in [
+            ("example.org\x0b", "/", None),
+            ("example.org\x00", "/", None),
+            ("example.org", "/a\rb", None),
+            ("example.org", "/a b", None),
+            ("example.org", "//evil.test/", None),
+            ("example.org", "evil", None),
+            ("example.org", "/a#b", None),
+            ("example.org", "/", Some("a\nb")),
+            ("example.org", "/", Some("a#b")),
+        ] {
+            assert_eq!(
+                None,
+                redirect.location(&request(host, path, query)),
+                "{:?}",
+                (host, path, query)
+            );
+        }
+    }
+}
@@ -0,0 +1,647 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+use crate::headers::Headers;
+
+/// The request methods the server understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+}
+
+impl Method {
+    pub fn parse(method: &str) -> Option<Method> {
+        // Methods are case-sensitive, "get" is not the same as "GET"
+        match method {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "PATCH" => Some(Method::Patch),
+            "OPTIONS" => Some(Method::Options),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+/// Everything that can go wrong while reading a request from a stream
+#[derive(Debug)]
+pub enum ParseError {
+    /// The client closed the connection before sending anything
+    ConnectionClosed,
+    /// The stream ended in the middle of the request
+    UnexpectedEof,
+    /// The first line is not `METHOD target HTTP/x.y`
+    InvalidRequestLine,
+    UnsupportedMethod(String),
+    UnsupportedVersion(String),
+    InvalidHeader(String),
+    /// `Content-Length` is not a plain number, or there are several that disagree
+    InvalidContentLength,
+    /// The request line and headers together are longer than `Limits::max_header_size`
+    HeadersTooLarge,
+    /// The body is more than `Limits::max_body_size`
+    BodyTooLarge,
+    /// Both `Content-Length` and `Transfer-Encoding`, which would let two
+    /// servers in a row disagree on where the body ends
+    AmbiguousLength,
+    /// A `Transfer-Encoding` other than chunked, which is the only one we decode
+    UnsupportedTransferEncoding(String),
+    /// A chunk size line that is not a hex number, or a chunk without its CRLF
+    InvalidChunk,
+    Io(io::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => write!(f, "connection closed by the client"),
+            ParseError::UnexpectedEof => write!(f, "request ended unexpectedly"),
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::UnsupportedMethod(method) => write!(f, "unsupported method {}", method),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported HTTP version {}", version)
+            }
+            ParseError::InvalidHeader(line) => write!(f, "malformed header line {:?}", line),
+            ParseError::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            ParseError::HeadersTooLarge => write!(f, "request headers are too large"),
+            ParseError::BodyTooLarge => write!(f, "request body is too large"),
+            ParseError::AmbiguousLength => {
+                write!(f, "both Content-Length and Transfer-Encoding are present")
+            }
+            ParseError::UnsupportedTransferEncoding(coding) => {
+                write!(f, "unsupported transfer encoding {}", coding)
+            }
+            ParseError::InvalidChunk => write!(f, "malformed chunk in the request body"),
+            ParseError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// This lets us use the ? operator on io::Result inside the parser
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
+/// How big a request may be, so a client can't make the server buffer without end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes in the request line and the headers, line endings included
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target without the query string, for example `/users/42`
+    pub path: String,
+    /// Whatever came after the `?` in the request target
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads one request from `reader`, with the default `Limits`
+    ///
+    /// The header block is read line by line until the empty line that ends it,
+    /// so there is no fixed buffer size. If there is a `Content-Length` header,
+    /// exactly that many bytes are read as the body, with
+    /// `Transfer-Encoding: chunked` the chunks are read until the last one.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        let limits = Limits::default();
+        let mut request = Request::read_head(reader, &limits)?;
+        request.read_body(reader, &limits)?;
+        Ok(request)
+    }
+
+    /// Reads the request line and the headers, the body is left in `reader`
+    ///
+    /// The server reads the two parts with different timeouts, `read_body` is
+    /// the other half.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        // What is left of max_header_size, every line takes its part
+        let mut left = limits.max_header_size;
+
+        let request_line = match read_line(reader, &mut left)? {
+            Some(line) => line,
+            None => return Err(ParseError::ConnectionClosed),
+        };
+
+        let (method, target, version) = parse_request_line(&request_line)?;
+
+        let mut headers = Headers::new();
+        loop {
+            let line = read_line(reader, &mut left)?.ok_or(ParseError::UnexpectedEof)?;
+            // An empty line marks the end of the headers
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = parse_header(&line)?;
+            headers.append(name, value);
+        }
+
+        // Splitting "/search?q=rust" into "/search" and "q=rust"
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+
+        Ok(Request {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+        })
+    }
+
+    /// Reads the body that `read_head` left behind
+    ///
+    /// If there is a `Content-Length` header, exactly that many bytes are read.
+    /// A body longer than `max_body_size` is refused before any of it is read.
+    /// A chunked body is refused as soon as the chunks add up to more than that.
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), ParseError> {
+        if self.headers.contains("Transfer-Encoding") {
+            if self.headers.contains("Content-Length") {
+                return Err(ParseError::AmbiguousLength);
+            }
+            let codings: Vec<&str> = self
+                .headers
+                .get_all("Transfer-Encoding")
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty())
+                .collect();
+            // "gzip, chunked" would need a gzip decoder as well
+            if !matches!(codings.as_slice(), [coding] if coding.eq_ignore_ascii_case("chunked")) {
+                return Err(ParseError::UnsupportedTransferEncoding(codings.join(", ")));
+            }
+            self.body = read_chunks(reader, limits)?;
+            return Ok(());
+        }
+
+        let length = match content_length(&self.headers)? {
+            Some(length) => length,
+            None => return Ok(()),
+        };
+        if length > limits.max_body_size {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        let mut body = Vec::with_capacity(length);
+        // take limits the reader to `length` bytes, so we never read into the next request
+        reader.take(length as u64).read_to_end(&mut body)?;
+
+        if body.len() < length {
+            return Err(ParseError::UnexpectedEof);
+        }
+
+        self.body = body;
+        Ok(())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+}
+
+// The length of the body, if there is a `Content-Length` header
+//
+// Only digits are accepted, "+3" or "3, 5" could be read differently by a
+// proxy in front of us, and then the two of us would disagree on where this
+// request ends and the next one starts. For the same reason several headers
+// are only fine if they all say the same.
+pub(crate) fn content_length(headers: &Headers) -> Result<Option<usize>, ParseError> {
+    let mut length = None;
+    for value in headers.get_all("Content-Length") {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let parsed = value
+            .parse::<usize>()
+            .map_err(|_| ParseError::InvalidContentLength)?;
+        if length.is_some_and(|length| length != parsed) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        length = Some(parsed);
+    }
+    Ok(length)
+}
+
+// A chunked body: chunks that start with their size in hex, until one of size 0
+//
+//     5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n
+//
+// The last chunk may be followed by trailer headers, which we read and drop,
+// nothing we do depends on them.
+fn read_chunks<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_chunk_line(reader)?;
+        // Chunk extensions after a ';' mean nothing to us
+        let digits = line.split(';').next().unwrap_or("").trim();
+        // from_str_radix would take a leading '+' too
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::InvalidChunk);
+        }
+        let size = usize::from_str_radix(digits, 16).map_err(|_| ParseError::InvalidChunk)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_size - body.len() {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        let start = body.len();
+        reader.take(size as u64).read_to_end(&mut body)?;
+        if body.len() - start < size {
+            return Err(ParseError::UnexpectedEof);
+        }
+        if !read_chunk_line(reader)?.is_empty() {
+            return Err(ParseError::InvalidChunk);
+        }
+    }
+
+    // Trailers, up to the empty line, count against the header limit
+    let mut left = limits.max_header_size;
+    loop {
+        let line = read_line(reader, &mut left)?.ok_or(ParseError::UnexpectedEof)?;
+        if line.is_empty() {
+            return Ok(body);
+        }
+        parse_header(&line)?;
+    }
+}
+
+// A chunk size line, or the CRLF after a chunk
+fn read_chunk_line<R: BufRead>(reader: &mut R) -> Result<String, ParseError> {
+    // Sizes are short, a long line is someone trying to make us buffer it
+    let mut left = 1024;
+    match read_line(reader, &mut left) {
+        Ok(Some(line)) => Ok(line),
+        Ok(None) => Err(ParseError::UnexpectedEof),
+        Err(ParseError::HeadersTooLarge | ParseError::InvalidHeader(_)) => {
+            Err(ParseError::InvalidChunk)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Reads a single line without the trailing "\r\n", taking its length from `left`
+// Returns None if the stream is already at its end
+fn read_line<R: BufRead>(reader: &mut R, left: &mut usize) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    // A line that doesn't end within what is left stops the reading right there
+    let read = reader.take(*left as u64).read_until(b'\n', &mut line)?;
+    *left -= read;
+
+    if read == 0 && *left > 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if *left == 0 {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        // The stream ended before the line was complete
+        return Err(ParseError::UnexpectedEof);
+    }
+
+    line.pop();
+    // Some clients only send "\n", so the "\r" is optional
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    // Header values are supposed to be ASCII, anything else is rejected
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(err) => Err(ParseError::InvalidHeader(
+            String::from_utf8_lossy(err.as_bytes()).into_owned(),
+        )),
+    }
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, Version), ParseError> {
+    let mut parts = line.split(' ');
+
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+
+    // A control character in the target could end up in a header we send back,
+    // a Location pointing to the same path for example
+    if target.is_empty() || target.contains(|c: char| c.is_control()) {
+        return Err(ParseError::InvalidRequestLine);
+    }
+
+    let method =
+        Method::parse(method).ok_or_else(|| ParseError::UnsupportedMethod(method.to_string()))?;
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        other if other.starts_with("HTTP/") => {
+            return Err(ParseError::UnsupportedVersion(other.to_string()))
+        }
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+
+    Ok((method, target, version))
+}
+
+fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    let invalid = || ParseError::InvalidHeader(line.to_string());
+
+    let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+
+    // No whitespace is allowed between the name and the colon
+    if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace() || c.is_control()) {
+        return Err(invalid());
+    }
+    // Tabs are fine in a value, other control characters (a lone "\r" above
+    // all) are not
+    let value = value.trim();
+    if value.contains(|c: char| c.is_control() && c != '\t') {
+        return Err(invalid());
+    }
+
+    Ok((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::read_from(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request =
+            parse("GET /search?q=rust HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n").unwrap();
+
+        assert_eq!(Method::Get, request.method);
+        assert_eq!("/search", request.path);
+        assert_eq!(Some("q=rust"), request.query.as_deref());
+        assert_eq!(Version::Http11, request.version);
+        assert_eq!(Some("localhost"), request.header("host"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn reads_body_from_content_length() {
+        let mut raw =
+            "POST /users HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n"
+                .as_bytes();
+
+        let first = Request::read_from(&mut raw).unwrap();
+        assert_eq!(Method::Post, first.method);
+        assert_eq!(b"hello", &first.body[..]);
+
+        // The second request must still be intact in the reader
+        let second = Request::read_from(&mut raw).unwrap();
+        assert_eq!("/", second.path);
+    }
+
+    #[test]
+    fn header_block_larger_than_a_buffer() {
+        let cookie = "x".repeat(4096);
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", cookie);
+
+        let request = parse(&raw).unwrap();
+        assert_eq!(Some(cookie.as_str()), request.header("Cookie"));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(matches!(parse(""), Err(ParseError::ConnectionClosed)));
+        assert!(matches!(
+            parse("GET /\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse("BREW / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::UnsupportedMethod(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nNo colon here\r\n\r\n"),
+            Err(ParseError::InvalidHeader(_))
+        ));
+        for target in ["/a\rb", "/a\tb", "/\x00", "/\x7f"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            assert!(
+                matches!(parse(&raw), Err(ParseError::InvalidRequestLine)),
+                "{:?}",
+                target
+            );
+        }
+        for value in ["a\rb", "a\x00b", "a\x1bb", "a\x7f"] {
+            let raw = format!("GET / HTTP/1.1\r\nX-Test: {}\r\n\r\n", value);
+            assert!(
+                matches!(parse(&raw), Err(ParseError::InvalidHeader(_))),
+                "{:?}",
+                value
+            );
+        }
+        assert_eq!(
+            Some("a\tb"),
+            parse("GET / HTTP/1.1\r\nX-Test: a\tb\r\n\r\n")
+                .unwrap()
+                .header("X-Test")
+        );
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        for length in ["+3", "-3", "3, 3", " "] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nabc", length);
+            assert!(
+                matches!(parse(&raw), Err(ParseError::InvalidContentLength)),
+                "{:?}",
+                length
+            );
+        }
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nabcde"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        // The same length twice is only redundant
+        assert_eq!(
+            b"abc",
+            &parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc")
+                .unwrap()
+                .body[..]
+        );
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x\r\n"),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let limits = Limits {
+            max_header_size: 40,
+            max_body_size: 4,
+        };
+        let read = |raw: &str| {
+            let mut reader = raw.as_bytes();
+            let mut request = Request::read_head(&mut reader, &limits)?;
+            request.read_body(&mut reader, &limits).map(|()| request)
+        };
+
+        // 16 + 18 + 2 bytes fit in 40
+        assert!(read("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").is_ok());
+        assert!(matches!(
+            read("GET / HTTP/1.1\r\nHost: localhost.localdomain\r\n\r\n"),
+            Err(ParseError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            read(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40))),
+            Err(ParseError::HeadersTooLarge)
+        ));
+        assert_eq!(
+            b"four",
+            &read("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nfour")
+                .unwrap()
+                .body[..]
+        );
+        assert!(matches!(
+            read("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nfive!"),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let raw = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n7;name=value\r\n, world\r\nA\r\n of chunks\r\n0\r\n\
+                   Expires: never\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut reader = raw.as_bytes();
+        let request = Request::read_from(&mut reader).unwrap();
+        assert_eq!(b"hello, world of chunks", &request.body[..]);
+        // The trailer is not part of the next request
+        assert_eq!(b"GET / HTTP/1.1\r\n\r\n", reader);
+
+        let limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n";
+        let mut reader = raw.as_bytes();
+        let mut request = Request::read_head(&mut reader, &limits).unwrap();
+        assert!(matches!(
+            request.read_body(&mut reader, &limits),
+            Err(ParseError::BodyTooLarge)
+        ));
+
+        for (raw, expected) in [
+            ("zz\r\nhello\r\n0\r\n\r\n", "InvalidChunk"),
+            ("+5\r\nhello\r\n0\r\n\r\n", "InvalidChunk"),
+            ("5\r\nhello!\r\n0\r\n\r\n", "InvalidChunk"),
+            ("5\r\nhel", "UnexpectedEof"),
+            ("5\r\nhello\r\n", "UnexpectedEof"),
+        ] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                raw
+            );
+            let err = parse(&raw).unwrap_err();
+            assert!(format!("{:?}", err).starts_with(expected), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn refuses_transfer_encodings_it_cant_trust() {
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            Err(ParseError::UnsupportedTransferEncoding(coding)) if coding == "gzip, chunked"
+        ));
+        assert!(matches!(
+            parse(
+                "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+            ),
+            Err(ParseError::AmbiguousLength)
+        ));
+    }
+}
@@ -0,0 +1,545 @@
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::SystemTime;
+
+use crate::date::format_http_date;
+use crate::headers::{has_line_break, Headers};
+
+/// The status codes the server sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    Ok,
+    Created,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    NotModified,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::NoContent => 204,
+            Status::PartialContent => 206,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::NotModified => 304,
+            Status::PermanentRedirect => 308,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::RequestTimeout => 408,
+            Status::PayloadTooLarge => 413,
+            Status::RangeNotSatisfiable => 416,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::NotModified => "Not Modified",
+            Status::PermanentRedirect => "Permanent Redirect",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::RequestTimeout => "Request Timeout",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+
+    // 1xx, 204 and 304 responses never have a body (RFC 9110, section 6.4.1)
+    pub(crate) fn allows_body(&self) -> bool {
+        !matches!(self, Status::NoContent | Status::NotModified)
+    }
+}
+
+/// The body of a response
+pub enum Body {
+    /// The whole body is already in memory
+    Bytes(Vec<u8>),
+    /// The body is read from `reader` while it is being sent, so big files
+    /// never have to fit in memory. `len` is what goes in `Content-Length`.
+    Stream {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    /// The length is not known up front, the body is sent with
+    /// `Transfer-Encoding: chunked` as `reader` produces it, until it ends
+    Chunked(Box<dyn Read + Send>),
+}
+
+impl Body {
+    /// The length that goes in `Content-Length`, 0 for a chunked body
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::Stream { len, .. } => *len,
+            Body::Chunked(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0 && !self.is_chunked()
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        matches!(self, Body::Chunked(_))
+    }
+
+    /// The body as a slice, if it is held in memory
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Stream { .. } | Body::Chunked(_) => None,
+        }
+    }
+
+    /// A chunked body that is written from somewhere else, a thread for example
+    ///
+    /// Everything written to the `BodyWriter` goes out as it arrives, and the
+    /// body ends when the writer is dropped. Writes fail once the client is gone.
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use std::thread;
+    /// use server::{Body, Response, Status};
+    ///
+    /// let (mut writer, body) = Body::channel();
+    /// thread::spawn(move || {
+    ///     for line in ["starting\n", "done\n"] {
+    ///         writer.write_all(line.as_bytes()).unwrap();
+    ///     }
+    /// });
+    /// let response = Response::new(Status::Ok).with_body(body);
+    /// ```
+    pub fn channel() -> (BodyWriter, Body) {
+        // A few chunks may wait, then a producer faster than the client has to wait too
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let reader = ChannelReader {
+            receiver,
+            chunk: Cursor::new(Vec::new()),
+        };
+        (BodyWriter { sender }, Body::Chunked(Box::new(reader)))
+    }
+
+    // Returns how many bytes of the body went out
+    fn write_to(&mut self, out: &mut dyn Write, chunked: bool) -> io::Result<u64> {
+        match self {
+            Body::Bytes(bytes) => out.write_all(bytes).map(|()| bytes.len() as u64),
+            Body::Stream { reader, len } => {
+                // take makes sure we never send more than Content-Length promised
+                let copied = io::copy(&mut reader.take(*len), out)?;
+                if copied < *len {
+                    // The file got shorter while we were sending it, the client
+                    // would wait forever for the missing bytes
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body ended before Content-Length bytes were sent",
+                    ));
+                }
+                Ok(copied)
+            }
+            Body::Chunked(reader) if chunked => write_chunks(reader, out),
+            // An HTTP/1.0 client knows the body ended when the connection closes
+            Body::Chunked(reader) => io::copy(reader, out),
+        }
+    }
+}
+
+// Each read becomes one chunk: its size in hex, the bytes, and a CRLF
+// A chunk of size 0 ends the body
+fn write_chunks(reader: &mut dyn Read, out: &mut dyn Write) -> io::Result<u64> {
+    let mut buffer = vec![0; 16 * 1024];
+    let mut total = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        // One write per chunk, the client may be waiting for each of them
+        let mut chunk = format!("{:x}\r\n", read).into_bytes();
+        chunk.extend_from_slice(&buffer[..read]);
+        chunk.extend_from_slice(b"\r\n");
+        out.write_all(&chunk)?;
+        out.flush()?;
+        total += read as u64;
+    }
+    out.write_all(b"0\r\n\r\n")?;
+    Ok(total)
+}
+
+/// Writes into a body made with `Body::channel`
+///
+/// Every write is a chunk of its own, wrap it in a `BufWriter` for many small ones.
+#[derive(Debug)]
+pub struct BodyWriter {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.sender.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            // The response is gone, most likely the client hung up
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The other end of a BodyWriter, every write comes out as one read or more
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk),
+                // Every writer is dropped, that's the end of the body
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+// Box<dyn Read> has no Debug implementation, so we write one that skips the reader
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Stream { len, .. } => f.debug_struct("Stream").field("len", len).finish(),
+            Body::Chunked(_) => f.write_str("Chunked"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+/// An HTTP response that handlers build and return
+///
+/// `Content-Length` (or `Transfer-Encoding` for a chunked body) and `Date` are
+/// filled in when the response is written, so handlers only set the headers
+/// they care about.
+///
+/// ```
+/// use server::{Response, Status};
+///
+/// let response = Response::new(Status::Ok)
+///     .with_header("Content-Type", "text/plain")
+///     .with_body("Hello!");
+///
+/// assert_eq!(Some(&b"Hello!"[..]), response.body.as_bytes());
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    pub status: Status,
+    pub headers: Headers,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: Status) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// A response with an HTML body
+    pub fn html(status: Status, body: impl Into<Body>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// A response with a plain text body
+    pub fn text(status: Status, body: impl Into<Body>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// Sets a header, replacing the previous value if there was one
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a header even if one with the same name is already there,
+    /// useful for headers like `Set-Cookie` that may be repeated
+    pub fn append_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Writes the status line, the headers and the body to `out`, and returns
+    /// how many bytes of body were sent
+    ///
+    /// It takes `&mut self` because a streamed body is consumed while it is written
+    pub fn write_to(&mut self, out: &mut dyn Write) -> io::Result<u64> {
+        self.write(out, true)
+    }
+
+    /// Like `write_to`, for an HTTP/1.0 client
+    ///
+    /// HTTP/1.0 has no chunked encoding, so a chunked body is sent as it is and
+    /// ends when the connection is closed. The caller has to close it.
+    pub fn write_to_http10(&mut self, out: &mut dyn Write) -> io::Result<u64> {
+        self.write(out, false)
+    }
+
+    fn write(&mut self, out: &mut dyn Write, chunked: bool) -> io::Result<u64> {
+        self.write_head(out, chunked)?;
+        let mut sent = 0;
+        if self.status.allows_body() {
+            sent = self.body.write_to(out, chunked)?;
+        }
+        out.flush()?;
+        Ok(sent)
+    }
+
+    /// Writes only the status line and the headers, which is the answer to a HEAD request
+    ///
+    /// `Content-Length` still announces the size the body would have had
+    pub fn write_head_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.write_head(out, true)?;
+        out.flush()
+    }
+
+    fn write_head(&self, out: &mut dyn Write, chunked: bool) -> io::Result<()> {
+        // Building the head in memory first means it goes out in a single write
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.code(),
+            self.status.reason()
+        );
+
+        for (name, value) in self.headers.iter() {
+            // These are computed below, a handler can't get them wrong
+            if ["Content-Length", "Transfer-Encoding", "Date"]
+                .iter()
+                .any(|computed| name.eq_ignore_ascii_case(computed))
+            {
+                continue;
+            }
+            // Headers refuses these already, a line break here would start a new field
+            if has_line_break(name) || has_line_break(value) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line break in the header field {}", name),
+                ));
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        head.push_str(&format!(
+            "Date: {}\r\n",
+            format_http_date(SystemTime::now())
+        ));
+        if !self.status.allows_body() {
+            // Neither header, there is no body
+        } else if !self.body.is_chunked() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        } else if chunked {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        head.push_str("\r\n");
+
+        out.write_all(head.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(mut response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_status_headers_and_body() {
+        let response = Response::html(Status::NotFound, "<h1>Oops</h1>")
+            .append_header("Set-Cookie", "a=1")
+            .append_header("Set-Cookie", "b=2");
+
+        let raw = serialize(response);
+
+        assert!(raw.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(raw.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(raw.contains("Set-Cookie: a=1\r\nSet-Cookie: b=2\r\n"));
+        assert!(raw.contains("Content-Length: 13\r\n"));
+        assert!(raw.contains("\r\nDate: "));
+        assert!(raw.ends_with("GMT\r\nContent-Length: 13\r\n\r\n<h1>Oops</h1>"));
+    }
+
+    #[test]
+    fn content_length_is_always_computed() {
+        let mut response = Response::new(Status::Ok)
+            .with_header("Content-Length", "999")
+            .with_body(vec![0u8, 159, 146, 150]);
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let head_end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&out[..head_end]);
+        assert!(head.contains("Content-Length: 4\r\n"));
+        assert!(!head.contains("999"));
+        assert_eq!(&[0u8, 159, 146, 150], &out[head_end..]);
+    }
+
+    #[test]
+    fn head_and_not_modified_have_no_body() {
+        let mut out = Vec::new();
+        Response::text(Status::Ok, "hello")
+            .write_head_to(&mut out)
+            .unwrap();
+        let raw = String::from_utf8(out).unwrap();
+        assert!(raw.contains("Content-Length: 5\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+
+        let raw = serialize(Response::new(Status::NotModified).with_body("ignored"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(!raw.contains("ignored"));
+    }
+
+    #[test]
+    fn streamed_body_is_copied_up_to_its_length() {
+        let reader = io::Cursor::new(b"0123456789".to_vec());
+        let response = Response::new(Status::Ok).with_body(Body::Stream {
+            reader: Box::new(reader),
+            len: 4,
+        });
+
+        let raw = serialize(response);
+        assert!(raw.ends_with("Content-Length: 4\r\n\r\n0123"));
+    }
+
+    #[test]
+    fn short_stream_is_an_error() {
+        let mut response = Response::new(Status::Ok).with_body(Body::Stream {
+            reader: Box::new(io::Cursor::new(b"abc".to_vec())),
+            len: 10,
+        });
+
+        let err = response.write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn chunked_bodies_are_sent_in_chunks() {
+        let (mut writer, body) = Body::channel();
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"chunks").unwrap();
+        drop(writer);
+        let mut response = Response::text(Status::Ok, body).with_header("Content-Length", "3");
+
+        let mut out = Vec::new();
+        assert_eq!(12, response.write_to(&mut out).unwrap());
+        let raw = String::from_utf8(out).unwrap();
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(raw.ends_with("\r\n\r\n6\r\nhello \r\n6\r\nchunks\r\n0\r\n\r\n"));
+
+        // HTTP/1.0 gets the bytes as they are
+        let (mut writer, body) = Body::channel();
+        writer.write_all(b"raw").unwrap();
+        drop(writer);
+        let mut out = Vec::new();
+        Response::text(Status::Ok, body)
+            .write_to_http10(&mut out)
+            .unwrap();
+        let raw = String::from_utf8(out).unwrap();
+        assert!(!raw.contains("Transfer-Encoding"));
+        assert!(raw.ends_with("GMT\r\n\r\nraw"));
+    }
+
+    #[test]
+    fn writes_fail_once_the_body_is_gone() {
+        let (mut writer, body) = Body::channel();
+        drop(body);
+        let err = writer.write(b"anyone?").unwrap_err();
+        assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::middleware::{Middleware, Next};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// A handler gets the request and the parameters captured from the path,
+/// and returns the response to send
+///
+/// Handlers are shared by every worker thread, so they must be Send + Sync
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync>;
+
+/// Values captured by `:name` and `*name` segments of a route pattern
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+// One piece of a pattern between two slashes
+#[derive(Debug)]
+enum Segment {
+    // "users" only matches "users"
+    Static(String),
+    // ":id" matches any single segment
+    Param(String),
+    // "*path" matches everything that is left, slashes included
+    Wildcard(String),
+}
+
+#[derive(Debug)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    /// # Panics
+    ///
+    /// Panics if a `*wildcard` segment is not the last one, since
+    /// routes are registered at startup this is a programming error
+    fn parse(pattern: &str) -> Pattern {
+        let parts: Vec<&str> = split_path(pattern).collect();
+        let mut segments = Vec::with_capacity(parts.len());
+
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(
+                    i == parts.len() - 1,
+                    "wildcard must be the last segment in {}",
+                    pattern
+                );
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Static(part.to_string())
+            };
+            segments.push(segment);
+        }
+
+        Pattern { segments }
+    }
+
+    // Returns the captured parameters if `path` matches the pattern
+    fn matches(&self, path: &str) -> Option<Params> {
+        let parts: Vec<&str> = split_path(path).collect();
+        let mut params = Params::default();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(expected) => {
+                    if parts.get(i) != Some(&expected.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let value = parts.get(i)?;
+                    params.values.push((name.clone(), value.to_string()));
+                }
+                Segment::Wildcard(name) => {
+                    // The rest of the path, which may be empty
+                    let rest = parts.get(i..).unwrap_or(&[]).join("/");
+                    params.values.push((name.clone(), rest));
+                    return Some(params);
+                }
+            }
+        }
+
+        // Every segment of the path must have been used
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+// "/users/42" -> ["users", "42"]
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Handler,
+}
+
+/// Dispatches requests to handlers registered by method and path pattern
+///
+/// Routes are tried in the order they were added and the first match wins.
+/// If no pattern matches the path the not found handler runs, and if a
+/// pattern matches but with another method the answer is `405 Method Not Allowed`.
+/// `HEAD` requests use the `GET` route when there is no `HEAD` route for the path.
+/// Middlewares added with `wrap` run around all of it.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_, _| Response::text(Status::NotFound, "Not Found")),
+            middlewares: Vec::new(),
+        }
+    }
+
+    /// Registers `handler` for `method` requests matching `pattern`
+    ///
+    /// Patterns look like `/users/:id` or `/static/*path`
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `*wildcard` segment is not at the end of the pattern
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// Replaces the default handler used when no route matches the path
+    pub fn not_found<F>(&mut self, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Adds `middleware` to the end of the chain every request goes through
+    ///
+    /// The first middleware added is the outermost one, it sees the request
+    /// first and the response last.
+    pub fn wrap<M>(&mut self, middleware: M) -> &mut Router
+    where
+        M: Middleware + 'static,
+    {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Runs `request` through the middlewares and the matching handler, and returns the response
+    pub fn handle(&self, request: &mut Request) -> Response {
+        Next::new(&self.middlewares, self).run(request)
+    }
+
+    // Finds the handler for `request`, the end of the middleware chain
+    pub(crate) fn dispatch(&self, request: &Request) -> Response {
+        // Methods of the routes whose pattern matched, for the Allow header
+        let mut allowed: Vec<Method> = Vec::new();
+        // A GET route that can answer a HEAD request if nothing better shows up
+        let mut head_fallback = None;
+
+        for route in &self.routes {
+            if let Some(params) = route.pattern.matches(&request.path) {
+                if route.method == request.method {
+                    return (route.handler)(request, &params);
+                }
+                if request.method == Method::Head
+                    && route.method == Method::Get
+                    && head_fallback.is_none()
+                {
+                    head_fallback = Some((route, params));
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method);
+                }
+            }
+        }
+
+        if let Some((route, params)) = head_fallback {
+            return (route.handler)(request, &params);
+        }
+
+        if allowed.is_empty() {
+            return (self.not_found)(request, &Params::default());
+        }
+
+        let allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+        Response::new(Status::MethodNotAllowed).with_header("Allow", &allow.join(", "))
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn dispatch(router: &Router, method: &str, path: &str) -> Response {
+        router.handle(&mut request(method, path))
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    fn echo_params(_: &Request, params: &Params) -> Response {
+        let mut body = String::new();
+        for (name, value) in params.iter() {
+            body.push_str(&format!("{}={};", name, value));
+        }
+        Response::text(Status::Ok, body)
+    }
+
+    #[test]
+    fn captures_named_parameters() {
+        let mut router = Router::new();
+        router.get("/users/:id/posts/:post", echo_params);
+
+        assert_eq!(
+            "id=42;post=7;",
+            body(dispatch(&router, "GET", "/users/42/posts/7"))
+        );
+        assert_eq!(
+            Status::NotFound,
+            dispatch(&router, "GET", "/users/42").status
+        );
+    }
+
+    #[test]
+    fn wildcard_takes_the_rest_of_the_path() {
+        let mut router = Router::new();
+        router.get("/static/*path", echo_params);
+
+        assert_eq!(
+            "path=css/site.css;",
+            body(dispatch(&router, "GET", "/static/css/site.css"))
+        );
+        assert_eq!("path=;", body(dispatch(&router, "GET", "/static")));
+    }
+
+    #[test]
+    fn first_registered_route_wins() {
+        let mut router = Router::new();
+        router.get("/users/me", |_, _| Response::text(Status::Ok, "me"));
+        router.get("/users/:id", echo_params);
+
+        assert_eq!("me", body(dispatch(&router, "GET", "/users/me")));
+        assert_eq!("id=1;", body(dispatch(&router, "GET", "/users/1")));
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow_header() {
+        let mut router = Router::new();
+        router.get("/users/:id", echo_params);
+        router.route(Method::Delete, "/users/:id", echo_params);
+
+        let response = dispatch(&router, "POST", "/users/1");
+        assert_eq!(Status::MethodNotAllowed, response.status);
+        assert_eq!(Some("GET, DELETE"), response.headers.get("Allow"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let mut router = Router::new();
+        router.get("/users/:id", echo_params);
+
+        let response = dispatch(&router, "HEAD", "/users/1");
+        assert_eq!(Status::Ok, response.status);
+        assert_eq!("id=1;", body(response));
+    }
+
+    #[test]
+    fn custom_not_found_handler() {
+        let mut router = Router::new();
+        router.not_found(|_, _| Response::text(Status::NotFound, "nothing here"));
+
+        assert_eq!("nothing here", body(dispatch(&router, "GET", "/missing")));
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_in_the_middle_panics() {
+        Router::new().get("/static/*path/more", echo_params);
+    }
+}
@@ -0,0 +1,339 @@
+// Jobs that borrow from the stack of whoever started them, like thread::scope
+//
+// A job for `execute` has to be 'static, because nothing stops the caller from
+// returning while the job still runs. `ThreadPool::scope` is what stops it: it
+// only returns once every job spawned in the scope is done, so the jobs may
+// borrow anything that lives longer than the call.
+
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::queue::{Job, Outcome};
+use crate::Shared;
+
+/// Spawns jobs that may borrow from outside the scope, see `ThreadPool::scope`
+pub struct Scope<'scope, 'env: 'scope> {
+    shared: &'scope Arc<Shared>,
+    state: Arc<State>,
+    // The same lifetimes as thread::Scope, 'scope is the scope itself
+    // and 'env is everything the jobs may borrow
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: Mutex<Jobs>,
+    // Signalled every time a job is done
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct Jobs {
+    running: usize,
+    // The first panic, it is passed on when the scope ends
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl State {
+    fn jobs(&self) -> MutexGuard<'_, Jobs> {
+        self.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// A scoped closure and the bookkeeping around it
+//
+// Whatever happens to it, running or being dropped with the pool, its drop is
+// what tells the scope the job is done, so scope can't return too early.
+struct ScopedTask {
+    task: Option<Box<dyn FnOnce() + Send>>,
+    state: Arc<State>,
+}
+
+impl ScopedTask {
+    fn run(mut self) -> Outcome {
+        let Some(task) = self.task.take() else {
+            return Outcome::Completed;
+        };
+        match panic::catch_unwind(AssertUnwindSafe(task)) {
+            Ok(()) => Outcome::Completed,
+            Err(payload) => {
+                self.state.jobs().panic.get_or_insert(payload);
+                Outcome::Panicked
+            }
+        }
+    }
+}
+
+impl Drop for ScopedTask {
+    fn drop(&mut self) {
+        // A task that is still here never ran, it is dropped before the scope
+        // hears about it, since it may hold borrows that end with the scope
+        drop(self.task.take());
+
+        self.state.jobs().running -= 1;
+        self.state.done.notify_all();
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Queues `f` on the pool, it may borrow anything that outlives the scope
+    ///
+    /// The scope waits for `f` anyway, so it is queued even when the queue is
+    /// full, whatever the `OverflowPolicy`, and nothing is dropped to make room.
+    /// Only when the pool is shutting down does `f` run right away on the
+    /// calling thread instead.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let task: Box<dyn FnOnce() + Send + 'scope> = Box::new(f);
+        // SAFETY: only the lifetime changes. `run` doesn't return before the
+        // drop of every ScopedTask has counted it as done, and the closure is
+        // gone by then, so nothing it borrows is used after 'scope ends.
+        let task: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(task) };
+
+        self.state.jobs().running += 1;
+        let scoped = ScopedTask {
+            task: Some(task),
+            state: Arc::clone(&self.state),
+        };
+
+        match self
+            .shared
+            .queue
+            .push_unbounded(Job::with_outcome(move || scoped.run()))
+        {
+            Ok(()) => self.shared.grow(),
+            Err(job) => {
+                (job.task)();
+            }
+        }
+    }
+
+    // Waits until every job of the scope is done
+    //
+    // Gives back the panic of a panic handler that ran in the meantime, it is
+    // only passed on once the wait is over.
+    fn wait(&self) -> Option<Box<dyn Any + Send>> {
+        // A worker of this pool waiting for its jobs could be the one they are
+        // waiting for, so it runs queued jobs in the meantime, ours or anyone's
+        let worker_id = self.shared.queue.worker_id();
+        let mut handler_panic = None;
+        loop {
+            if let Some(id) = worker_id {
+                if let Some(job) = self.shared.queue.try_pop() {
+                    // The panic handler is allowed to panic, leaving here early
+                    // would free what the jobs still running borrow
+                    let run = panic::catch_unwind(AssertUnwindSafe(|| self.shared.run(id, job)));
+                    if let Err(payload) = run {
+                        handler_panic.get_or_insert(payload);
+                    }
+                    continue;
+                }
+            }
+
+            let jobs = self.state.jobs();
+            if jobs.running == 0 {
+                return handler_panic;
+            }
+            // The guard is dropped right away, the loop checks again anyway
+            if worker_id.is_some() {
+                // New jobs may be queued any moment without anyone telling us
+                drop(
+                    self.state
+                        .done
+                        .wait_timeout(jobs, Duration::from_millis(10)),
+                );
+            } else {
+                drop(self.state.done.wait(jobs));
+            }
+        }
+    }
+}
+
+// The body of ThreadPool::scope
+pub(crate) fn run<'env, F, R>(shared: &Arc<Shared>, f: F) -> R
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+{
+    let scope = Scope {
+        shared,
+        state: Arc::default(),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+
+    // The jobs are waited for even if f panics, the unwinding would free what they borrow
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    let handler_panic = scope.wait();
+
+    let jobs = mem::take(&mut *scope.state.jobs());
+    let result = match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    };
+    if let Some(payload) = handler_panic {
+        panic::resume_unwind(payload);
+    }
+    if let Some(payload) = jobs.panic {
+        panic::resume_unwind(payload);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{OverflowPolicy, ThreadPool};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(3);
+        let mut numbers: Vec<u32> = (1..=10).collect();
+        let total = AtomicUsize::new(0);
+
+        pool.scope(|s| {
+            for chunk in numbers.chunks_mut(3) {
+                let total = &total;
+                s.spawn(move || {
+                    for n in chunk {
+                        *n *= 2;
+                        total.fetch_add(*n as usize, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(vec![2, 4, 6, 8, 10, 12, 14, 16, 18, 20], numbers);
+        assert_eq!(110, total.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panics_are_passed_on_after_every_job() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("scoped"));
+                s.spawn(|| {
+                    std::thread::sleep(Duration::from_millis(50));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            })
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(Some(&"scoped"), payload.downcast_ref::<&str>());
+        assert_eq!(1, finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn scope_on_the_only_worker_does_not_deadlock() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let (sender, receiver) = mpsc::channel();
+
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            let mut values = [0; 4];
+            // Nobody else is left to run these, so the waiting worker does
+            inner.scope(|s| {
+                for (i, value) in values.iter_mut().enumerate() {
+                    s.spawn(move || *value = i);
+                }
+            });
+            sender.send(values).unwrap();
+        });
+        assert_eq!(
+            [0, 1, 2, 3],
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+
+        // The pool must not be dropped on its own worker
+        while Arc::strong_count(&pool) > 1 {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn a_panicking_handler_does_not_end_the_wait_early() {
+        let pool = Arc::new(ThreadPool::new(2));
+        pool.set_panic_handler(|_| panic!("the handler failed too"));
+        let (sender, receiver) = mpsc::channel();
+
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            let mut value = 0;
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                inner.scope(|s| {
+                    let (started, running) = mpsc::channel();
+                    let value = &mut value;
+                    // Taken by the other worker, which is still on it when the
+                    // waiting worker runs the job below
+                    s.spawn(move || {
+                        started.send(()).unwrap();
+                        std::thread::sleep(Duration::from_millis(100));
+                        *value = 1;
+                    });
+                    running.recv().unwrap();
+                    inner.execute(|| panic!("a sibling job"));
+                })
+            }));
+            let payload = result.unwrap_err();
+            sender
+                .send((value, payload.downcast_ref::<&str>().copied()))
+                .unwrap();
+        });
+        assert_eq!(
+            (1, Some("the handler failed too")),
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+
+        while Arc::strong_count(&pool) > 1 {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn full_queues_take_every_scoped_job() {
+        for policy in [OverflowPolicy::Reject, OverflowPolicy::DropOldest] {
+            let pool = ThreadPool::builder()
+                .size(1)
+                .queue_capacity(1)
+                .overflow_policy(policy)
+                .build()
+                .unwrap();
+            let caller = std::thread::current().id();
+            let (started, running) = mpsc::channel();
+            let (release, wait) = mpsc::channel::<()>();
+            let on_workers = AtomicUsize::new(0);
+
+            pool.scope(|s| {
+                // The first job keeps the worker busy and the second fills the queue
+                s.spawn(move || {
+                    started.send(()).unwrap();
+                    let _ = wait.recv();
+                });
+                running.recv().unwrap();
+                for _ in 0..3 {
+                    let on_workers = &on_workers;
+                    s.spawn(move || {
+                        if std::thread::current().id() != caller {
+                            on_workers.fetch_add(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+                drop(release);
+            });
+
+            assert_eq!(3, on_workers.load(Ordering::SeqCst), "{:?}", policy);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often the accept loop checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A handle that tells the server to stop
+///
+/// Clones share the same state, so one clone can be given to the accept loop,
+/// one to every connection and one to whatever decides when to stop.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    watch_signals: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        if self.watch_signals.load(Ordering::SeqCst) && signals::received() {
+            self.trigger();
+        }
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Triggers the shutdown when the process gets SIGINT (Ctrl+C) or SIGTERM
+    ///
+    /// Only Unix has signals, on other platforms this does nothing
+    pub fn listen_for_signals(&self) -> io::Result<()> {
+        signals::install()?;
+        self.watch_signals.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Like `listener.incoming()`, but the iterator ends once the shutdown is triggered
+    ///
+    /// The listener is switched to non-blocking mode, so a blocked `accept`
+    /// can't keep the server alive after the shutdown.
+    pub fn incoming<'a>(&'a self, listener: &'a TcpListener) -> Incoming<'a> {
+        Incoming {
+            listener,
+            shutdown: self,
+        }
+    }
+}
+
+/// Iterator returned by [`Shutdown::incoming`]
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+    shutdown: &'a Shutdown,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        if let Err(err) = self.listener.set_nonblocking(true) {
+            return Some(Err(err));
+        }
+
+        while !self.shutdown.is_triggered() {
+            match self.listener.accept() {
+                // Some platforms let the accepted stream inherit the non-blocking mode,
+                // but the connection code expects blocking reads with timeouts
+                Ok((stream, _)) => return Some(stream.set_nonblocking(false).map(|_| stream)),
+                // No one is connecting right now, check the flag again a bit later
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::io;
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    // signal() returns SIG_ERR, which is -1 cast to a pointer, when it fails
+    const SIG_ERR: usize = usize::MAX;
+
+    // A signal handler can interrupt any code, so all it does is set this flag
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    extern "C" fn handle_signal(_signum: c_int) {
+        RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn install() -> io::Result<()> {
+        for signum in [SIGINT, SIGTERM] {
+            // Safe because handle_signal only touches an atomic,
+            // which is allowed inside a signal handler
+            if unsafe { signal(signum, handle_signal) } == SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn received() -> bool {
+        RECEIVED.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(not(unix))]
+mod signals {
+    use std::io;
+
+    pub fn install() -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn received() -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn clones_share_the_trigger() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+
+        shutdown.trigger();
+        assert!(clone.is_triggered());
+    }
+
+    #[test]
+    fn incoming_stops_after_trigger() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+
+        let handle = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || shutdown.incoming(&listener).count())
+        };
+
+        TcpStream::connect(address).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        shutdown.trigger();
+
+        assert_eq!(1, handle.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sigterm_triggers_the_shutdown() {
+        extern "C" {
+            fn raise(signum: std::os::raw::c_int) -> std::os::raw::c_int;
+        }
+
+        let shutdown = Shutdown::new();
+        shutdown.listen_for_signals().unwrap();
+        assert!(!shutdown.is_triggered());
+
+        // Safe because our handler for SIGTERM is installed, so the process keeps running
+        assert_eq!(0, unsafe { raise(15) });
+        assert!(shutdown.is_triggered());
+    }
+}
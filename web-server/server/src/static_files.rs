@@ -0,0 +1,481 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::date::{format_http_date, parse_http_date};
+use crate::mime;
+use crate::range::{self, Multipart, Ranges};
+use crate::request::{Method, Request};
+use crate::response::{Body, Response, Status};
+
+/// Serves files from a document root
+///
+/// ```no_run
+/// use server::{Router, StaticFiles};
+///
+/// let files = StaticFiles::new("public");
+/// let mut router = Router::new();
+/// router.get("/*path", move |request, params| {
+///     files.serve(request, params.get("path").unwrap_or(""))
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Answers `request` with the file at `path`, relative to the document root
+    ///
+    /// `path` is usually the `*path` parameter of the route. It may still be
+    /// percent-encoded, and any attempt to leave the root with `..` gets a 403.
+    ///
+    /// Files come with an `ETag` and a `Last-Modified` header. A client that
+    /// sends them back in `If-None-Match` or `If-Modified-Since` gets a
+    /// `304 Not Modified` without the body if the file didn't change.
+    ///
+    /// A `Range` header gets only the bytes it asks for, as a `206 Partial
+    /// Content`, unless `If-Range` says the client's copy is out of date. Either
+    /// way the file is streamed from disk.
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let relative = match resolve(path) {
+            Ok(relative) => relative,
+            Err(status) => return Response::text(status, status.reason()),
+        };
+
+        let mut full_path = self.root.join(relative);
+
+        let metadata = match fs::metadata(&full_path) {
+            Ok(metadata) => metadata,
+            Err(err) => return error_response(&err),
+        };
+
+        if metadata.is_dir() {
+            // Without the trailing slash, relative links inside index.html
+            // would be resolved against the parent directory
+            if !request.path.ends_with('/') {
+                let location = format!("{}/", request.path);
+                return Response::new(Status::MovedPermanently).with_header("Location", &location);
+            }
+            full_path.push("index.html");
+        }
+
+        match open(&full_path, request) {
+            Ok(response) => response,
+            Err(err) => error_response(&err),
+        }
+    }
+}
+
+// Opens the file and builds a response that streams it, or a 304 if the client has it
+fn open(path: &Path, request: &Request) -> io::Result<Response> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+
+    // A directory without an index.html
+    if !metadata.is_file() {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+
+    let modified = metadata.modified().ok();
+    let etag = etag(&metadata, modified);
+    let last_modified = modified.map(format_http_date);
+    let mut response = Response::new(Status::Ok).with_header("ETag", &etag);
+    if let Some(last_modified) = &last_modified {
+        response.headers.insert("Last-Modified", last_modified);
+    }
+
+    let content_type = mime::from_path(path);
+    if not_modified(request, &etag, modified) {
+        // No body, but the type tells a middleware like Compression what it stands for
+        response.status = Status::NotModified;
+        return Ok(response.with_header("Content-Type", content_type));
+    }
+
+    let len = metadata.len();
+    response.headers.insert("Accept-Ranges", "bytes");
+
+    let ranges = match request.header("Range") {
+        // Only GET has ranges, and only if the client's copy is the one we have
+        Some(header)
+            if request.method == Method::Get
+                && if_range(request, &etag, last_modified.as_deref()) =>
+        {
+            range::parse(header, len)
+        }
+        _ => Ranges::Ignored,
+    };
+
+    match ranges {
+        Ranges::Ignored => Ok(response
+            .with_header("Content-Type", content_type)
+            .with_body(Body::Stream {
+                reader: Box::new(file),
+                len,
+            })),
+        Ranges::Unsatisfiable => {
+            let content_range = format!("bytes */{}", len);
+            response.status = Status::RangeNotSatisfiable;
+            Ok(response
+                .with_header("Content-Range", &content_range)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_body(Status::RangeNotSatisfiable.reason()))
+        }
+        Ranges::Satisfiable(ranges) => {
+            response.status = Status::PartialContent;
+            if let [(first, last)] = ranges[..] {
+                let mut file = file;
+                file.seek(SeekFrom::Start(first))?;
+                let part = last - first + 1;
+                let content_range = format!("bytes {}-{}/{}", first, last, len);
+                return Ok(response
+                    .with_header("Content-Type", content_type)
+                    .with_header("Content-Range", &content_range)
+                    .with_body(Body::Stream {
+                        reader: Box::new(file.take(part)),
+                        len: part,
+                    }));
+            }
+
+            let multipart = Multipart::new(&ranges, len, content_type);
+            let content_type = format!("multipart/byteranges; boundary={}", multipart.boundary());
+            let body_len = multipart.len();
+            Ok(response
+                .with_header("Content-Type", &content_type)
+                .with_body(Body::Stream {
+                    reader: Box::new(multipart.reader(file)),
+                    len: body_len,
+                }))
+        }
+    }
+}
+
+// The size and the modification time, so any change to the file changes it,
+// without reading the contents like a hash would
+fn etag(metadata: &Metadata, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!(
+        "\"{:x}-{:x}-{:x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+// Whether the client's copy is still good (RFC 9110, section 13.2.2)
+//
+// If-None-Match wins when both are sent, the ETag sees changes within the
+// same second that Last-Modified can't.
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if !matches!(request.method, Method::Get | Method::Head) {
+        return false;
+    }
+
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        // The weak comparison, a compressed copy of the file is just as good
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || weak_eq(tag, etag));
+    }
+
+    match (request.header("If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => match parse_http_date(since) {
+            // Last-Modified has whole seconds, the file's time may have more
+            Some(since) => modified
+                .duration_since(since)
+                .map_or(true, |newer| newer.as_secs() == 0),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+// Whether a Range header should be used (RFC 9110, section 13.1.5)
+//
+// Without If-Range it always is. With it, the client only wants the range if its
+// copy is still current, otherwise it needs the whole new file anyway. That
+// takes a strong match, a weak tag or a different date means no.
+fn if_range(request: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match request.header("If-Range").map(str::trim) {
+        None => true,
+        Some(tag) if tag.starts_with('"') => tag == etag,
+        Some(tag) if tag.starts_with("W/") => false,
+        Some(date) => match (
+            parse_http_date(date),
+            last_modified.and_then(parse_http_date),
+        ) {
+            (Some(date), Some(last_modified)) => date == last_modified,
+            _ => false,
+        },
+    }
+}
+
+// Two entity tags are weakly equal if they match after dropping "W/"
+fn weak_eq(a: &str, b: &str) -> bool {
+    let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    opaque(a) == opaque(b)
+}
+
+fn error_response(err: &io::Error) -> Response {
+    let status = match err.kind() {
+        io::ErrorKind::NotFound => Status::NotFound,
+        io::ErrorKind::PermissionDenied => Status::Forbidden,
+        _ => {
+            crate::error!("Failed to read static file: {}", err);
+            Status::InternalServerError
+        }
+    };
+    Response::text(status, status.reason())
+}
+
+// Turns the request path into a relative path that can't leave the root
+//
+// The path is decoded first, otherwise "%2e%2e/" would sneak a ".." past the check
+fn resolve(path: &str) -> Result<PathBuf, Status> {
+    let decoded = percent_decode(path).ok_or(Status::BadRequest)?;
+
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            // "//" and "/./" don't change the path
+            "" | "." => {}
+            ".." => return Err(Status::Forbidden),
+            // A backslash is a separator on Windows and a NUL byte ends
+            // the string for the OS, neither belongs in a file name
+            _ if segment.contains(['\\', '\0']) => return Err(Status::Forbidden),
+            _ => relative.push(segment),
+        }
+    }
+
+    Ok(relative)
+}
+
+// Decodes "%20" style escapes, returns None for bad escapes or invalid UTF-8
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Every test gets its own directory, tests run in parallel
+    fn document_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("static-files-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0xff]).unwrap();
+        fs::write(root.join("my file.txt"), "spaces").unwrap();
+        root
+    }
+
+    fn get(files: &StaticFiles, path: &str) -> Response {
+        get_with(files, path, &[])
+    }
+
+    fn get_with(files: &StaticFiles, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        let request = Request::read_from(&mut raw.as_bytes()).unwrap();
+        files.serve(&request, path.trim_start_matches('/'))
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let mut body = Vec::new();
+        match response.body {
+            Body::Stream { mut reader, .. } | Body::Chunked(mut reader) => {
+                reader.read_to_end(&mut body).unwrap();
+            }
+            Body::Bytes(bytes) => body = bytes,
+        }
+        body
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let files = StaticFiles::new(document_root("types"));
+
+        let response = get(&files, "/logo.png");
+        assert_eq!(Status::Ok, response.status);
+        assert_eq!(Some("image/png"), response.headers.get("Content-Type"));
+        assert_eq!(6, response.body.len());
+        assert_eq!(vec![0x89, b'P', b'N', b'G', 0, 0xff], body(response));
+
+        let response = get(&files, "/my%20file.txt");
+        assert_eq!(b"spaces".to_vec(), body(response));
+    }
+
+    #[test]
+    fn directories_serve_their_index() {
+        let files = StaticFiles::new(document_root("dirs"));
+
+        assert_eq!(b"<h1>home</h1>".to_vec(), body(get(&files, "/")));
+        assert_eq!(b"<h1>docs</h1>".to_vec(), body(get(&files, "/docs/")));
+
+        let redirect = get(&files, "/docs");
+        assert_eq!(Status::MovedPermanently, redirect.status);
+        assert_eq!(Some("/docs/"), redirect.headers.get("Location"));
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let files = StaticFiles::new(document_root("traversal"));
+
+        assert_eq!(Status::Forbidden, get(&files, "/../Cargo.toml").status);
+        assert_eq!(
+            Status::Forbidden,
+            get(&files, "/docs/../../etc/passwd").status
+        );
+        assert_eq!(Status::Forbidden, get(&files, "/%2e%2e/Cargo.toml").status);
+        assert_eq!(Status::Forbidden, get(&files, "/..%5cCargo.toml").status);
+        assert_eq!(Status::BadRequest, get(&files, "/%zz").status);
+    }
+
+    #[test]
+    fn unchanged_files_are_304() {
+        let files = StaticFiles::new(document_root("conditional"));
+
+        let response = get(&files, "/index.html");
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        let modified = response.headers.get("Last-Modified").unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+        let response = get_with(&files, "/index.html", &[("If-None-Match", &etag)]);
+        assert_eq!(Status::NotModified, response.status);
+        assert_eq!(Some(etag.as_str()), response.headers.get("ETag"));
+        assert_eq!(
+            Some("text/html; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+        assert!(response.body.is_empty());
+
+        // A compressed copy has a weak tag, it still counts
+        let weak = format!("\"other\", W/{}", etag);
+        let response = get_with(&files, "/index.html", &[("If-None-Match", &weak)]);
+        assert_eq!(Status::NotModified, response.status);
+
+        let response = get_with(&files, "/index.html", &[("If-Modified-Since", &modified)]);
+        assert_eq!(Status::NotModified, response.status);
+
+        // If-None-Match decides when both are there
+        let response = get_with(
+            &files,
+            "/index.html",
+            &[
+                ("If-None-Match", "\"old\""),
+                ("If-Modified-Since", &modified),
+            ],
+        );
+        assert_eq!(Status::Ok, response.status);
+
+        let old = "Thu, 01 Jan 1970 00:00:00 GMT";
+        let response = get_with(&files, "/index.html", &[("If-Modified-Since", old)]);
+        assert_eq!(Status::Ok, response.status);
+        assert_eq!(b"<h1>home</h1>".to_vec(), body(response));
+    }
+
+    #[test]
+    fn single_and_multiple_ranges() {
+        let files = StaticFiles::new(document_root("ranges"));
+
+        let response = get_with(&files, "/index.html", &[("Range", "bytes=4-8")]);
+        assert_eq!(Status::PartialContent, response.status);
+        assert_eq!(Some("bytes 4-8/13"), response.headers.get("Content-Range"));
+        assert_eq!(5, response.body.len());
+        assert_eq!(b"home<".to_vec(), body(response));
+
+        let response = get_with(&files, "/index.html", &[("Range", "bytes=0-3,-5")]);
+        assert_eq!(Status::PartialContent, response.status);
+        let content_type = response.headers.get("Content-Type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let len = response.body.len();
+        let body = String::from_utf8(body(response)).unwrap();
+        assert_eq!(len, body.len() as u64);
+        assert!(body.starts_with(&format!("--{}\r\n", boundary)));
+        assert!(body.contains("Content-Range: bytes 0-3/13\r\n\r\n<h1>\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-12/13\r\n\r\n</h1>\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+
+    #[test]
+    fn ranges_that_cant_be_served() {
+        let files = StaticFiles::new(document_root("bad-ranges"));
+
+        let response = get_with(&files, "/index.html", &[("Range", "bytes=100-")]);
+        assert_eq!(Status::RangeNotSatisfiable, response.status);
+        assert_eq!(Some("bytes */13"), response.headers.get("Content-Range"));
+
+        // A header we don't understand gets the whole file
+        let response = get_with(&files, "/index.html", &[("Range", "lines=1-2")]);
+        assert_eq!(Status::Ok, response.status);
+        assert_eq!(Some("bytes"), response.headers.get("Accept-Ranges"));
+    }
+
+    #[test]
+    fn if_range_needs_the_current_version() {
+        let files = StaticFiles::new(document_root("if-range"));
+        let current = get(&files, "/index.html");
+        let etag = current.headers.get("ETag").unwrap();
+        let modified = current.headers.get("Last-Modified").unwrap();
+
+        for (validator, status) in [
+            (etag, Status::PartialContent),
+            (modified, Status::PartialContent),
+            ("\"old\"", Status::Ok),
+            (&format!("W/{}", etag), Status::Ok),
+            ("Thu, 01 Jan 1970 00:00:00 GMT", Status::Ok),
+        ] {
+            let response = get_with(
+                &files,
+                "/index.html",
+                &[("Range", "bytes=0-3"), ("If-Range", validator)],
+            );
+            assert_eq!(status, response.status, "If-Range: {}", validator);
+        }
+    }
+
+    #[test]
+    fn missing_files_are_404() {
+        let files = StaticFiles::new(document_root("missing"));
+
+        assert_eq!(Status::NotFound, get(&files, "/nope.html").status);
+    }
+}
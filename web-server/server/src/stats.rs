@@ -0,0 +1,243 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the histogram buckets, from 100µs to 10s
+const BOUNDS_MICROS: [u64; 16] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+/// A snapshot of what the pool is doing, returned by `ThreadPool::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// Workers running a job right now
+    pub active: usize,
+    /// Workers waiting for a job
+    pub idle: usize,
+    /// Jobs that returned normally
+    pub completed: u64,
+    /// Jobs that panicked, including `spawn` jobs whose panic went to their handle
+    pub panicked: u64,
+    /// How long jobs waited in the queue before a worker took them
+    pub queue_time: Histogram,
+    /// How long jobs ran
+    pub run_time: Histogram,
+}
+
+/// Durations counted in buckets, the way Prometheus histograms work
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// The upper bound of every bucket and how many durations were at most that long
+    ///
+    /// The counts are cumulative, so the last one is every duration up to 10s.
+    /// Longer ones only show up in `count`.
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let nanos = self.sum.as_nanos() / u128::from(self.count);
+        Some(Duration::from_nanos(nanos as u64))
+    }
+}
+
+impl PoolStats {
+    /// Formats the stats in the Prometheus text format, every name starts with `prefix`
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut out = String::new();
+
+        // Writing to a String can't fail, so the results are ignored
+        let _ = writeln!(
+            out,
+            "# HELP {}_queued_jobs Jobs waiting for a worker",
+            prefix
+        );
+        let _ = writeln!(out, "# TYPE {}_queued_jobs gauge", prefix);
+        let _ = writeln!(out, "{}_queued_jobs {}", prefix, self.queued);
+
+        let _ = writeln!(out, "# HELP {}_workers Worker threads by state", prefix);
+        let _ = writeln!(out, "# TYPE {}_workers gauge", prefix);
+        let _ = writeln!(
+            out,
+            "{}_workers{{state=\"active\"}} {}",
+            prefix, self.active
+        );
+        let _ = writeln!(out, "{}_workers{{state=\"idle\"}} {}", prefix, self.idle);
+
+        let _ = writeln!(out, "# HELP {}_jobs_total Finished jobs by outcome", prefix);
+        let _ = writeln!(out, "# TYPE {}_jobs_total counter", prefix);
+        let _ = writeln!(
+            out,
+            "{}_jobs_total{{outcome=\"completed\"}} {}",
+            prefix, self.completed
+        );
+        let _ = writeln!(
+            out,
+            "{}_jobs_total{{outcome=\"panicked\"}} {}",
+            prefix, self.panicked
+        );
+
+        let name = format!("{}_job_queue_seconds", prefix);
+        write_histogram(
+            &mut out,
+            &name,
+            "Time jobs waited for a worker",
+            &self.queue_time,
+        );
+        let name = format!("{}_job_run_seconds", prefix);
+        write_histogram(&mut out, &name, "Time jobs took to run", &self.run_time);
+
+        out
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in &histogram.buckets {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            bound.as_secs_f64(),
+            count
+        );
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+    let _ = writeln!(out, "{}_sum {}", name, histogram.sum.as_secs_f64());
+    let _ = writeln!(out, "{}_count {}", name, histogram.count);
+}
+
+// The counters behind PoolStats, updated by the workers
+// Atomics keep the bookkeeping off the locks the scheduler just got rid of
+#[derive(Default)]
+pub(crate) struct Metrics {
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    queue_time: Recorder,
+    run_time: Recorder,
+}
+
+impl Metrics {
+    pub(crate) fn job_started(&self, waited: Duration) {
+        self.queue_time.record(waited);
+        self.active.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_finished(&self, ran: Duration, panicked: bool) {
+        self.run_time.record(ran);
+        let counter = if panicked {
+            &self.panicked
+        } else {
+            &self.completed
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(&self, queued: usize, workers: usize) -> PoolStats {
+        let active = self.active.load(Ordering::SeqCst);
+        PoolStats {
+            queued,
+            active,
+            // A worker may finish between the two reads, so this can't go below 0
+            idle: workers.saturating_sub(active),
+            completed: self.completed.load(Ordering::SeqCst),
+            panicked: self.panicked.load(Ordering::SeqCst),
+            queue_time: self.queue_time.snapshot(),
+            run_time: self.run_time.snapshot(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Recorder {
+    // Not cumulative here, every duration goes into one bucket
+    buckets: [AtomicU64; BOUNDS_MICROS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Recorder {
+    fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        // Past the last bound it is only counted
+        if let Some(bucket) = BOUNDS_MICROS.iter().position(|&bound| micros <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let mut total = 0;
+        let buckets = BOUNDS_MICROS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (Duration::from_micros(bound), total)
+            })
+            .collect();
+
+        Histogram {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let recorder = Recorder::default();
+        recorder.record(Duration::from_micros(50));
+        recorder.record(Duration::from_millis(3));
+        recorder.record(Duration::from_secs(60));
+
+        let histogram = recorder.snapshot();
+        assert_eq!(3, histogram.count);
+        assert_eq!((Duration::from_micros(100), 1), histogram.buckets[0]);
+        assert_eq!((Duration::from_micros(5_000), 2), histogram.buckets[5]);
+        // The 60s job is past the last bucket
+        assert_eq!(2, histogram.buckets.last().unwrap().1);
+        assert_eq!(
+            Some(Duration::from_micros(60_003_050) / 3),
+            histogram.mean()
+        );
+    }
+
+    #[test]
+    fn prometheus_text_format() {
+        let metrics = Metrics::default();
+        metrics.job_started(Duration::from_micros(200));
+        metrics.job_finished(Duration::from_millis(2), false);
+        metrics.job_started(Duration::from_micros(200));
+
+        let text = metrics.snapshot(3, 4).to_prometheus("pool");
+
+        assert!(text.contains("# TYPE pool_queued_jobs gauge\npool_queued_jobs 3\n"));
+        assert!(text.contains("pool_workers{state=\"active\"} 1\n"));
+        assert!(text.contains("pool_workers{state=\"idle\"} 3\n"));
+        assert!(text.contains("pool_jobs_total{outcome=\"completed\"} 1\n"));
+        assert!(text.contains("pool_jobs_total{outcome=\"panicked\"} 0\n"));
+        assert!(text.contains("pool_job_queue_seconds_bucket{le=\"0.0001\"} 0\n"));
+        assert!(text.contains("pool_job_queue_seconds_bucket{le=\"0.00025\"} 2\n"));
+        assert!(text.contains("pool_job_queue_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("pool_job_run_seconds_sum 0.002\n"));
+        assert!(text.contains("pool_job_run_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,420 @@
+// Jobs that run later or over and over, for `execute_after` and `execute_every`
+//
+// One timer thread per pool keeps the scheduled jobs in a heap ordered by when
+// they are due. It sleeps until the first one is due and then queues it like
+// `execute` would, so the job itself runs on a worker. The thread is only
+// started by the first scheduled job, a pool that never uses timers has none.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::atomic::{self, AtomicBool, AtomicU8};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::queue::Job;
+use crate::Shared;
+
+// What a TimerHandle's state can be
+const SCHEDULED: u8 = 0;
+const CANCELLED: u8 = 1;
+// A one-off job went to the queue, it can't be stopped anymore
+const QUEUED: u8 = 2;
+
+/// Cancels a job started with `execute_after` or `execute_every`
+///
+/// Dropping the handle doesn't cancel anything, the job just can't be stopped anymore.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    state: Arc<AtomicU8>,
+}
+
+impl TimerHandle {
+    /// Stops the job from being queued again, returns `false` if it was too late
+    ///
+    /// A run that was already queued or is running still finishes.
+    /// For `execute_after` it is too late once the job is queued,
+    /// and for `execute_every` only when it was cancelled before.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .compare_exchange(
+                SCHEDULED,
+                CANCELLED,
+                atomic::Ordering::SeqCst,
+                atomic::Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(atomic::Ordering::SeqCst) == CANCELLED
+    }
+}
+
+enum Task {
+    Once(Box<dyn FnOnce() + Send>),
+    Every {
+        interval: Duration,
+        f: Arc<dyn Fn() + Send + Sync>,
+        // Set while a run is queued or running, so slow runs don't pile up
+        running: Arc<AtomicBool>,
+    },
+}
+
+struct Entry {
+    due: Instant,
+    // Entries due at the same time keep the order they were scheduled in
+    sequence: u64,
+    state: Arc<AtomicU8>,
+    task: Task,
+}
+
+// BinaryHeap is a max-heap, so the order is reversed to get the earliest entry on top
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (other.due, other.sequence).cmp(&(self.due, self.sequence))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+#[derive(Default)]
+struct State {
+    entries: BinaryHeap<Entry>,
+    next_sequence: u64,
+    closed: bool,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Default)]
+pub(crate) struct Timer {
+    state: Mutex<State>,
+    // Signalled when an entry is added or the timer is closed
+    changed: Condvar,
+}
+
+impl Timer {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Adds a job to the heap, starting the timer thread for the first one
+    fn schedule(shared: &Arc<Shared>, due: Instant, task: Task) -> io::Result<TimerHandle> {
+        let timer = &shared.timer;
+        let mut state = timer.state();
+        if state.thread.is_none() && !state.closed {
+            let shared = Arc::clone(shared);
+            let thread = thread::Builder::new()
+                .name("timer".to_string())
+                .spawn(move || run(&shared))?;
+            state.thread = Some(thread);
+        }
+
+        // A stopped pool takes no new jobs, the handle says so
+        let handle = TimerHandle {
+            state: Arc::new(AtomicU8::new(if state.closed {
+                CANCELLED
+            } else {
+                SCHEDULED
+            })),
+        };
+        if !state.closed {
+            let sequence = state.next_sequence;
+            state.next_sequence += 1;
+            state.entries.push(Entry {
+                due,
+                sequence,
+                state: Arc::clone(&handle.state),
+                task,
+            });
+            timer.changed.notify_one();
+        }
+        Ok(handle)
+    }
+
+    /// Drops every scheduled job and tells the thread to stop
+    pub(crate) fn close(&self) {
+        let mut state = self.state();
+        state.closed = true;
+        state.entries.clear();
+        self.changed.notify_one();
+    }
+
+    /// Waits for the thread after `close`
+    pub(crate) fn join(&self) {
+        let thread = self.state().thread.take();
+        if let Some(thread) = thread {
+            if thread.join().is_err() {
+                crate::warn!("The timer thread had panicked.");
+            }
+        }
+    }
+}
+
+pub(crate) fn execute_after<F>(shared: &Arc<Shared>, delay: Duration, f: F) -> TimerHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    schedule(shared, delay, Task::Once(Box::new(f)))
+}
+
+pub(crate) fn execute_every<F>(shared: &Arc<Shared>, interval: Duration, f: F) -> TimerHandle
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    assert!(
+        !interval.is_zero(),
+        "execute_every needs an interval above 0"
+    );
+    let task = Task::Every {
+        interval,
+        f: Arc::new(f),
+        running: Arc::default(),
+    };
+    schedule(shared, interval, task)
+}
+
+fn schedule(shared: &Arc<Shared>, delay: Duration, task: Task) -> TimerHandle {
+    Timer::schedule(shared, Instant::now() + delay, task).unwrap_or_else(|err| {
+        // Like a job refused by the queue, the caller finds out from the handle
+        crate::error!("Failed to start the timer thread: {}", err);
+        TimerHandle {
+            state: Arc::new(AtomicU8::new(CANCELLED)),
+        }
+    })
+}
+
+// The timer thread, it sleeps until the next entry is due
+fn run(shared: &Arc<Shared>) {
+    let timer = &shared.timer;
+    let mut state = timer.state();
+    loop {
+        if state.closed {
+            return;
+        }
+
+        let now = Instant::now();
+        let due = match state.entries.peek() {
+            Some(entry) => entry.due,
+            None => {
+                state = timer
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+        };
+        if due > now {
+            state = timer
+                .changed
+                .wait_timeout(state, due - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            continue;
+        }
+
+        let Some(entry) = state.entries.pop() else {
+            continue;
+        };
+        // Queueing may wait for room, new entries shouldn't have to wait with it
+        drop(state);
+        let next = fire(shared, entry);
+        state = timer.state();
+        if let Some(next) = next {
+            if !state.closed {
+                state.entries.push(next);
+            }
+        }
+    }
+}
+
+// Queues the job of a due entry, and returns the entry for the next run of a periodic one
+fn fire(shared: &Arc<Shared>, entry: Entry) -> Option<Entry> {
+    match entry.task {
+        Task::Once(f) => {
+            let queued = entry.state.compare_exchange(
+                SCHEDULED,
+                QUEUED,
+                atomic::Ordering::SeqCst,
+                atomic::Ordering::SeqCst,
+            );
+            if queued.is_ok() {
+                submit(shared, Job::new(f));
+            }
+            None
+        }
+        Task::Every {
+            interval,
+            ref f,
+            ref running,
+        } => {
+            if entry.state.load(atomic::Ordering::SeqCst) == CANCELLED {
+                return None;
+            }
+
+            // The last run is not done yet, this one is skipped
+            if !running.swap(true, atomic::Ordering::SeqCst) {
+                let (f, running) = (Arc::clone(f), Arc::clone(running));
+                submit(
+                    shared,
+                    Job::new(move || {
+                        // Cleared even if f panics, the next run still happens
+                        let _running = Running(running);
+                        f();
+                    }),
+                );
+            }
+
+            // Runs that were missed (a full queue, a busy timer) are not made up
+            let mut due = entry.due + interval;
+            let now = Instant::now();
+            if due <= now {
+                due = now + interval;
+            }
+            Some(Entry { due, ..entry })
+        }
+    }
+}
+
+fn submit(shared: &Arc<Shared>, job: Job) {
+    if let Err(err) = shared.submit(job) {
+        crate::warn!("Dropped a scheduled job: {}", err);
+    }
+}
+
+// Clears the running flag of a periodic job when its run ends
+// A job that was dropped without running clears it too
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    #[test]
+    fn delayed_job_waits_for_its_time() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        let start = Instant::now();
+
+        pool.execute_after(Duration::from_millis(100), move || {
+            sender.send(start.elapsed()).unwrap();
+        });
+
+        let waited = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(waited >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn earlier_jobs_run_first() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+
+        for (n, delay) in [(3, 150), (1, 50), (2, 100)] {
+            let sender = sender.clone();
+            pool.execute_after(Duration::from_millis(delay), move || {
+                sender.send(n).unwrap();
+            });
+        }
+
+        let order: Vec<u32> = receiver.iter().take(3).collect();
+        assert_eq!(vec![1, 2, 3], order);
+    }
+
+    #[test]
+    fn cancelled_job_never_runs() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&ran);
+        let handle = pool.execute_after(Duration::from_millis(50), move || {
+            flag.store(true, atomic::Ordering::SeqCst);
+        });
+        assert!(handle.cancel());
+        assert!(handle.is_cancelled());
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(!ran.load(atomic::Ordering::SeqCst));
+        // Once it is cancelled, cancelling again does nothing
+        assert!(!handle.cancel());
+    }
+
+    #[test]
+    fn periodic_job_repeats_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&runs);
+        let handle = pool.execute_every(Duration::from_millis(20), move || {
+            counter.fetch_add(1, atomic::Ordering::SeqCst);
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(handle.cancel());
+        // A run that was queued before the cancel may still finish
+        thread::sleep(Duration::from_millis(50));
+        let after_cancel = runs.load(atomic::Ordering::SeqCst);
+        assert!(after_cancel >= 3, "only {} runs", after_cancel);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(after_cancel, runs.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn slow_periodic_runs_do_not_overlap() {
+        let pool = ThreadPool::new(4);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let (now, max) = (Arc::clone(&running), Arc::clone(&most));
+        let handle = pool.execute_every(Duration::from_millis(10), move || {
+            let count = now.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            max.fetch_max(count, atomic::Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            now.fetch_sub(1, atomic::Ordering::SeqCst);
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        handle.cancel();
+        drop(pool);
+        assert_eq!(1, most.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stopping_the_pool_drops_the_timers() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&ran);
+        pool.execute_after(Duration::from_secs(60), move || {
+            flag.store(true, atomic::Ordering::SeqCst);
+        });
+
+        // Doesn't wait for the minute to pass
+        let start = Instant::now();
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!ran.load(atomic::Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,241 @@
+// HTTPS, with rustls doing the encryption
+//
+// A TLS connection starts with a handshake: the client says which host it
+// wants (SNI, Server Name Indication), the server answers with the certificate
+// for that host and both agree on keys. After that the bytes are the same HTTP
+// as on a plain connection, only encrypted, so the rest of the server doesn't
+// need to know. Certificates and keys are read from PEM files, the format
+// Let's Encrypt and openssl write.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+/// A TLS connection on top of a TCP one, encrypting and decrypting as it goes
+pub(crate) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Everything that can go wrong while loading certificates
+#[derive(Debug)]
+pub enum TlsError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A PEM file without a certificate or a key in it
+    Pem(String),
+    /// rustls refused a certificate or key, for example one that doesn't match
+    Rustls(rustls::Error),
+    /// The certificate for a name doesn't cover that name
+    WrongName {
+        name: String,
+        error: rustls::Error,
+    },
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Io { path, error } => write!(f, "can't read {}: {}", path.display(), error),
+            TlsError::Pem(message) => write!(f, "{}", message),
+            TlsError::Rustls(error) => write!(f, "{}", error),
+            TlsError::WrongName { name, error } => {
+                write!(f, "the certificate for {} doesn't fit: {}", name, error)
+            }
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Io { error, .. } => Some(error),
+            TlsError::Rustls(error) | TlsError::WrongName { error, .. } => Some(error),
+            TlsError::Pem(_) => None,
+        }
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(error: rustls::Error) -> TlsError {
+        TlsError::Rustls(error)
+    }
+}
+
+/// A certificate chain and the private key that goes with it
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    key: CertifiedKey,
+}
+
+impl Certificate {
+    /// Reads the chain from `certificate` and the key from `private_key`, both PEM files
+    pub fn load(certificate: &Path, private_key: &Path) -> Result<Certificate, TlsError> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|error| TlsError::Io {
+                path: path.to_path_buf(),
+                error,
+            })
+        };
+        Certificate::from_pem(&read(certificate)?, &read(private_key)?).map_err(|err| match err {
+            TlsError::Pem(message) => TlsError::Pem(format!(
+                "{} and {}: {}",
+                certificate.display(),
+                private_key.display(),
+                message
+            )),
+            err => err,
+        })
+    }
+
+    /// The same from PEM text, the site's own certificate first and then the ones that signed it
+    pub fn from_pem(certificate: &[u8], private_key: &[u8]) -> Result<Certificate, TlsError> {
+        let chain = CertificateDer::pem_slice_iter(certificate)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| TlsError::Pem(format!("bad certificate: {}", err)))?;
+        if chain.is_empty() {
+            return Err(TlsError::Pem("no certificate found".to_string()));
+        }
+        let private_key = PrivateKeyDer::from_pem_slice(private_key)
+            .map_err(|err| TlsError::Pem(format!("bad private key: {}", err)))?;
+
+        // This also checks that the key belongs to the certificate
+        let key = CertifiedKey::from_der(chain, private_key, &ring::default_provider())?;
+        Ok(Certificate { key })
+    }
+}
+
+/// What HTTPS listeners need to accept connections, see `Tls::builder`
+///
+/// Cloning it is cheap, every connection gets its own handle.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    config: Arc<ServerConfig>,
+}
+
+impl Tls {
+    /// `default` is sent to clients that ask for a name with no certificate of its own,
+    /// or for none at all
+    pub fn builder(default: Certificate) -> TlsBuilder {
+        TlsBuilder {
+            default,
+            names: Vec::new(),
+        }
+    }
+
+    // The handshake happens later, on the first read or write
+    pub(crate) fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection =
+            ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// Adds certificates for more names before building a `Tls`
+///
+/// ```no_run
+/// use std::path::Path;
+/// use server::{Certificate, Tls};
+///
+/// let site = Certificate::load(Path::new("site.pem"), Path::new("site.key")).unwrap();
+/// let blog = Certificate::load(Path::new("blog.pem"), Path::new("blog.key")).unwrap();
+/// let tls = Tls::builder(site).sni("blog.example.org", blog).build().unwrap();
+/// ```
+pub struct TlsBuilder {
+    default: Certificate,
+    names: Vec<(String, Certificate)>,
+}
+
+impl TlsBuilder {
+    /// Sends `certificate` to clients that ask for `name`
+    pub fn sni(mut self, name: &str, certificate: Certificate) -> TlsBuilder {
+        self.names.push((name.to_string(), certificate));
+        self
+    }
+
+    /// Fails if a certificate given to `sni` is not valid for its name
+    pub fn build(self) -> Result<Tls, TlsError> {
+        let mut names = ResolvesServerCertUsingSni::new();
+        for (name, certificate) in self.names {
+            names
+                .add(&name, certificate.key)
+                .map_err(|error| TlsError::WrongName { name, error })?;
+        }
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(Resolver {
+                names,
+                default: Arc::new(self.default.key),
+            }));
+        // Only HTTP/1.1 is spoken here, a client that offers h2 learns it right away
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Tls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+// Picks the certificate by the name the client asked for
+#[derive(Debug)]
+struct Resolver {
+    names: ResolvesServerCertUsingSni,
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.names
+            .resolve(client_hello)
+            .or_else(|| Some(Arc::clone(&self.default)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed(name: &str) -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (certified.cert.pem(), certified.signing_key.serialize_pem())
+    }
+
+    #[test]
+    fn certificates_must_fit_their_key_and_name() {
+        let (cert, key) = self_signed("localhost");
+        let (other_cert, other_key) = self_signed("example.test");
+
+        let localhost = Certificate::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+        let example = Certificate::from_pem(other_cert.as_bytes(), other_key.as_bytes()).unwrap();
+        assert!(Tls::builder(localhost.clone())
+            .sni("example.test", example.clone())
+            .build()
+            .is_ok());
+
+        assert!(matches!(
+            Tls::builder(localhost).sni("localhost", example).build(),
+            Err(TlsError::WrongName { name, .. }) if name == "localhost"
+        ));
+        assert!(matches!(
+            Certificate::from_pem(cert.as_bytes(), other_key.as_bytes()),
+            Err(TlsError::Rustls(_))
+        ));
+        // A key where the certificate should be
+        assert!(matches!(
+            Certificate::from_pem(key.as_bytes(), key.as_bytes()),
+            Err(TlsError::Pem(_))
+        ));
+    }
+}
@@ -0,0 +1,41 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use server::connection::{handle_connection, ConnectionOptions};
+use server::Router;
+
+// Starts a server on a random free port and returns its address
+// Every connection gets its own thread, the pool is not what these tests are about
+pub fn start(router: Router, options: ConnectionOptions) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = Arc::new(router);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let router = Arc::clone(&router);
+            let options = options.clone();
+            thread::spawn(move || handle_connection(stream, &router, &options));
+        }
+    });
+
+    address
+}
+
+// Sends `raw` and returns everything the server answers until it closes the connection
+pub fn exchange(address: SocketAddr, raw: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    // If the server never closes the connection the test fails instead of hanging
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(raw).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
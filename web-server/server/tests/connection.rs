@@ -0,0 +1,253 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use server::connection::ConnectionOptions;
+use server::{AccessLog, Body, Limits, LogFormat, Response, Router, Status};
+
+mod common;
+
+fn router() -> Router {
+    let mut router = Router::new();
+    router.get("/:name", |_, params| {
+        Response::text(Status::Ok, format!("hello {}", params.get("name").unwrap()))
+    });
+    router
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let address = common::start(router(), ConnectionOptions::default());
+
+    let response = common::exchange(
+        address,
+        b"GET /ferris HTTP/1.1\r\n\r\nGET /crab HTTP/1.1\r\n\r\nGET /last HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+
+    assert_eq!(3, response.matches("HTTP/1.1 200 OK").count());
+    let ferris = response.find("hello ferris").unwrap();
+    let crab = response.find("hello crab").unwrap();
+    let last = response.find("hello last").unwrap();
+    assert!(ferris < crab && crab < last);
+    assert!(response.contains("Connection: close\r\n"));
+}
+
+#[test]
+fn idle_connections_are_closed_after_the_timeout() {
+    let options = ConnectionOptions {
+        keep_alive_timeout: Duration::from_millis(200),
+        ..ConnectionOptions::default()
+    };
+    let address = common::start(router(), options);
+
+    let start = Instant::now();
+    let response = common::exchange(address, b"GET /idle HTTP/1.1\r\n\r\n");
+
+    // The connection stayed open after the answer and closed once it was idle
+    assert!(response.contains("hello idle"));
+    assert!(!response.contains("Connection: close"));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn http_1_0_closes_unless_asked_to_keep_alive() {
+    let address = common::start(router(), ConnectionOptions::default());
+
+    let response = common::exchange(
+        address,
+        b"GET /old HTTP/1.0\r\n\r\nGET /ignored HTTP/1.0\r\n\r\n",
+    );
+    assert_eq!(1, response.matches("HTTP/1.1 200 OK").count());
+    assert!(response.contains("Connection: close\r\n"));
+
+    let response = common::exchange(
+        address,
+        b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n",
+    );
+    assert_eq!(2, response.matches("HTTP/1.1 200 OK").count());
+    assert!(response.contains("Connection: keep-alive\r\n"));
+}
+
+#[test]
+fn connection_closes_after_max_requests() {
+    let options = ConnectionOptions {
+        max_requests: 2,
+        ..ConnectionOptions::default()
+    };
+    let address = common::start(router(), options);
+
+    let response = common::exchange(
+        address,
+        b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n",
+    );
+
+    assert_eq!(2, response.matches("HTTP/1.1 200 OK").count());
+    assert!(!response.contains("hello 3"));
+}
+
+#[test]
+fn malformed_request_gets_400_and_closes() {
+    let address = common::start(router(), ConnectionOptions::default());
+
+    let response = common::exchange(address, b"NONSENSE\r\n\r\nGET /a HTTP/1.1\r\n\r\n");
+
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(!response.contains("hello a"));
+}
+
+#[test]
+fn rejected_connections_get_a_503() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server::connection::reject_connection(stream);
+    });
+
+    let response = common::exchange(address, b"GET /ferris HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(response.contains("Retry-After: 1\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+}
+
+// Collects the access log lines in memory
+#[derive(Clone, Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn answered_requests_are_logged() {
+    let lines = Lines::default();
+    let options = ConnectionOptions {
+        access_log: Some(Arc::new(AccessLog::new(lines.clone(), LogFormat::Common))),
+        ..ConnectionOptions::default()
+    };
+    let address = common::start(router(), options);
+
+    common::exchange(
+        address,
+        b"GET /ferris?x=1 HTTP/1.1\r\n\r\nHEAD /crab HTTP/1.1\r\n\r\nNONSENSE\r\n\r\n",
+    );
+
+    let log = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(3, lines.len());
+    assert!(lines[0].starts_with("127.0.0.1 - - ["));
+    assert!(lines[0].contains("] \"GET /ferris?x=1 HTTP/1.1\" 200 12 "));
+    // HEAD sends no body, and the malformed request has no request line to show
+    assert!(lines[1].contains("] \"HEAD /crab HTTP/1.1\" 200 - "));
+    assert!(lines[2].contains("] \"-\" 400 "));
+}
+
+#[test]
+fn slow_requests_get_a_408() {
+    let options = ConnectionOptions {
+        header_timeout: Duration::from_millis(300),
+        ..ConnectionOptions::default()
+    };
+    let address = common::start(router(), options);
+
+    // A byte every 50ms never waits long enough for a read to time out,
+    // the request as a whole still has to make it in time
+    let mut stream = TcpStream::connect(address).unwrap();
+    let mut reader = stream.try_clone().unwrap();
+    let answer = std::thread::spawn(move || {
+        let mut response = Vec::new();
+        let _ = reader.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).into_owned()
+    });
+    let start = Instant::now();
+    for byte in b"GET /slowloris HTTP/1.1\r\nX-Padding: ".iter().cycle() {
+        // Once the server gave up the writes start failing
+        if answer.is_finished() || stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let response = answer.join().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+}
+
+#[test]
+fn oversized_requests_get_a_431_or_a_413() {
+    let options = ConnectionOptions {
+        limits: Limits {
+            max_header_size: 64,
+            max_body_size: 8,
+        },
+        ..ConnectionOptions::default()
+    };
+    let address = common::start(router(), options);
+
+    let big_header = format!("GET /a HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(100));
+    let response = common::exchange(address, big_header.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+    let response = common::exchange(
+        address,
+        b"POST /a HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789",
+    );
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+}
+
+#[test]
+fn chunked_bodies_both_ways() {
+    let mut router = Router::new();
+    router.post("/echo", |request, _| {
+        Response::text(Status::Ok, request.body.to_ascii_uppercase())
+    });
+    // A body of unknown length, written by another thread as it goes
+    router.get("/count", |_, _| {
+        let (mut writer, body) = Body::channel();
+        thread::spawn(move || {
+            for i in 1..=3 {
+                writer.write_all(format!("{}\n", i).as_bytes()).unwrap();
+            }
+        });
+        Response::text(Status::Ok, body)
+    });
+    let address = common::start(router, ConnectionOptions::default());
+
+    let response = common::exchange(
+        address,
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+          6\r\nchunky\r\n7\r\n upload\r\n0\r\n\r\n\
+          GET /count HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    let (echo, count) = response.split_at(response.rfind("HTTP/1.1 200 OK").unwrap());
+    assert!(echo.ends_with("Content-Length: 13\r\n\r\nCHUNKY UPLOAD"));
+    assert!(count.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(!count.contains("Content-Length"));
+    assert!(count.ends_with("\r\n\r\n2\r\n1\n\r\n2\r\n2\n\r\n2\r\n3\n\r\n0\r\n\r\n"));
+
+    // HTTP/1.0 has no chunks, the end of the connection is the end of the body
+    let response = common::exchange(
+        address,
+        b"GET /count HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+    );
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(response.ends_with("\r\n\r\n1\n2\n3\n"));
+
+    let response = common::exchange(
+        address,
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+}
@@ -0,0 +1,139 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use server::connection::{handle_tls_connection, ConnectionOptions};
+use server::{Certificate, Response, Router, Status, Tls};
+
+// A self-signed certificate for `name`, made fresh for every test run
+fn self_signed(name: &str) -> (Certificate, CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    let certificate = Certificate::from_pem(
+        certified.cert.pem().as_bytes(),
+        certified.signing_key.serialize_pem().as_bytes(),
+    )
+    .unwrap();
+    (certificate, certified.cert.der().clone())
+}
+
+// Starts an HTTPS server on a random free port
+fn start(tls: Tls) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut router = Router::new();
+    router.get("/:name", |request, params| {
+        let name = params.get("name").unwrap();
+        let host = request.header("Host").unwrap_or("nobody");
+        Response::text(Status::Ok, format!("hello {} from {}", name, host))
+    });
+    let router = Arc::new(router);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (router, tls) = (Arc::clone(&router), tls.clone());
+            thread::spawn(move || {
+                handle_tls_connection(
+                    stream.unwrap(),
+                    &tls,
+                    &router,
+                    &ConnectionOptions::default(),
+                )
+            });
+        }
+    });
+    address
+}
+
+// A client that trusts only `roots`, connected to `address` and asking for `name`
+fn connect(
+    address: SocketAddr,
+    name: &str,
+    roots: &[CertificateDer<'static>],
+) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root.clone()).unwrap();
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    // Like a browser, which would rather speak HTTP/2
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let name = ServerName::try_from(name.to_string()).unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+
+    let stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    StreamOwned::new(connection, stream)
+}
+
+#[test]
+fn serves_requests_over_tls() {
+    let (certificate, root) = self_signed("localhost");
+    let address = start(Tls::builder(certificate).build().unwrap());
+
+    let mut stream = connect(address, "localhost", &[root]);
+    stream
+        .write_all(
+            b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    // read_to_end fails if the server hangs up without saying so first
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert_eq!(Some(&b"http/1.1"[..]), stream.conn.alpn_protocol());
+    assert_eq!(2, response.matches("HTTP/1.1 200 OK\r\n").count());
+    assert!(response.contains("hello first from localhost"));
+    assert!(response.ends_with("hello second from localhost"));
+}
+
+#[test]
+fn picks_the_certificate_by_name() {
+    let (localhost, localhost_root) = self_signed("localhost");
+    let (example, example_root) = self_signed("example.test");
+    let tls = Tls::builder(localhost)
+        .sni("example.test", example)
+        .build()
+        .unwrap();
+    let address = start(tls);
+    let roots = [localhost_root.clone(), example_root.clone()];
+
+    for (name, expected) in [
+        ("example.test", &example_root),
+        ("localhost", &localhost_root),
+    ] {
+        let mut stream = connect(address, name, &roots);
+        let request = format!(
+            "GET /sni HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            name
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            Some(expected),
+            stream
+                .conn
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+        );
+        assert!(response.ends_with(&format!("hello sni from {}", name)));
+    }
+
+    // A name without a certificate of its own gets the default one, which
+    // the client refuses since it's for another name
+    let mut stream = connect(address, "other.test", &roots);
+    assert!(stream.write_all(b"GET / HTTP/1.1\r\n\r\n").is_err());
+}